@@ -0,0 +1,215 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+use crate::core::host_key::HostKeyChecking;
+use crate::core::ssh_config::HostConfig;
+
+const DEFAULT_PORT: u16 = 22;
+
+/// 命令行中输入的服务器地址,支持以下格式:
+/// `host`, `user@host`, `user@host:port`, `[ipv6]:port`, `ssh://user@host:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: &str| anyhow!("invalid address '{}': {}", s, msg);
+        let rest = match s.strip_prefix("ssh://") {
+            Some(rest) => rest.strip_suffix('/').unwrap_or(rest),
+            None => s,
+        };
+        if rest.contains('/') {
+            return Err(err("path is not supported"));
+        }
+
+        let (user, rest) = match rest.rsplit_once('@') {
+            Some(("", _)) => return Err(err("empty user")),
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, rest),
+        };
+
+        let parse_port = |p: &str| p.parse::<u16>().map_err(|_| err("invalid port"));
+        let (host, port) = if let Some(rest) = rest.strip_prefix('[') {
+            let (host, after) = rest.split_once(']').ok_or(err("missing ']'"))?;
+            match after {
+                "" => (host, None),
+                _ => match after.strip_prefix(':') {
+                    Some(p) => (host, Some(parse_port(p)?)),
+                    None => return Err(err("unexpected characters after ']'")),
+                },
+            }
+        } else if rest.matches(':').count() > 1 {
+            // 不带方括号的ipv6地址,无法指定端口
+            (rest, None)
+        } else {
+            match rest.split_once(':') {
+                Some((host, p)) => (host, Some(parse_port(p)?)),
+                None => (rest, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(err("empty host"));
+        }
+
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            write!(f, "{}", self.host)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// 合并地址、命令行参数和 ~/.ssh/config 后得到的实际登录信息
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub identity_files: Vec<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+}
+
+impl Target {
+    /// 优先级: 地址中的值 > 命令行参数 > ~/.ssh/config > 默认值
+    pub fn resolve(
+        address: &str,
+        port: Option<u16>,
+        host_key_checking: Option<HostKeyChecking>,
+    ) -> anyhow::Result<Self> {
+        let address: Address = address.parse()?;
+        if let (Some(a), Some(p)) = (address.port, port) {
+            if a != p {
+                bail!("port {} in address conflicts with port option {}", a, p);
+            }
+        }
+        let config = HostConfig::resolve(&address.host)?;
+
+        let user = address
+            .user
+            .or(config.user)
+            .or_else(|| env::var("USER").ok())
+            .or_else(|| env::var("LOGNAME").ok())
+            .ok_or(anyhow!("no user given for {}, use user@host", address.host))?;
+        let host = config.hostname.unwrap_or(address.host);
+        let port = address
+            .port
+            .or(port)
+            .or(config.port)
+            .unwrap_or(DEFAULT_PORT);
+        let host_key_checking = host_key_checking
+            .or(config
+                .strict_host_key_checking
+                .as_deref()
+                .and_then(HostKeyChecking::from_ssh_config))
+            .unwrap_or_default();
+
+        Ok(Self {
+            user,
+            host,
+            port,
+            identity_files: config.identity_files,
+            host_key_checking,
+        })
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let address = Address {
+            user: Some(self.user.clone()),
+            host: self.host.clone(),
+            port: Some(self.port),
+        };
+        write!(f, "{}", address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    fn address(user: Option<&str>, host: &str, port: Option<u16>) -> Address {
+        Address {
+            user: user.map(|u| u.to_string()),
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_forms() {
+        assert_eq!(parse("host"), address(None, "host", None));
+        assert_eq!(parse("root@host"), address(Some("root"), "host", None));
+        assert_eq!(
+            parse("root@host:2222"),
+            address(Some("root"), "host", Some(2222))
+        );
+        assert_eq!(
+            parse("ssh://root@host:2222/"),
+            address(Some("root"), "host", Some(2222))
+        );
+        assert_eq!(parse("[::1]:2222"), address(None, "::1", Some(2222)));
+        assert_eq!(
+            parse("root@[fe80::1]"),
+            address(Some("root"), "fe80::1", None)
+        );
+        assert_eq!(parse("fe80::1"), address(None, "fe80::1", None));
+        // 用户名中可以包含@,以最后一个@分隔
+        assert_eq!(parse("a@b@host"), address(Some("a@b"), "host", None));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "",
+            "@host",
+            "root@",
+            "host:port",
+            "host:70000",
+            "[::1",
+            "[::1]x",
+            "ssh://host/path",
+        ] {
+            assert!(s.parse::<Address>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in ["host", "root@host:22", "[::1]:2222", "root@[fe80::1]"] {
+            assert_eq!(parse(s).to_string(), s);
+            assert_eq!(parse(&parse(s).to_string()), parse(s));
+        }
+    }
+}
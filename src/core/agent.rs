@@ -1,11 +1,16 @@
-use std::fs;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
 use std::path::Path;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use log::info;
-use ssh2::Session;
+use ssh2::{Channel, Session};
+use tempfile::NamedTempFile;
+
+use crate::core::address::Target;
+use crate::core::auth::Auth;
+use crate::core::host_key;
 
 pub struct Agent {
     session: Session,
@@ -18,12 +23,19 @@ impl Agent {
         })
     }
 
-    pub fn login(&mut self, username: &str, host: &str) -> anyhow::Result<()> {
-        info!("tcp connect...");
-        let tcp = TcpStream::connect(host)?;
+    /// 启用ssh压缩,需要在登录前设置
+    pub fn set_compress(&self, compress: bool) {
+        self.session.set_compress(compress);
+    }
+
+    pub fn login(&mut self, target: &Target, auth: &mut Auth) -> anyhow::Result<()> {
+        info!("tcp connect {}...", target);
+        let tcp = TcpStream::connect((target.host.as_str(), target.port))
+            .map_err(|e| anyhow!("connect {} failed: {}", target, e))?;
         self.session.set_tcp_stream(tcp);
         self.session.handshake()?;
-        self.session.userauth_agent(username)?;
+        host_key::verify(&self.session, target)?;
+        auth.authenticate(&self.session, target)?;
         if !self.session.authenticated() {
             bail!("authentication failed");
         }
@@ -31,53 +43,134 @@ impl Agent {
         Ok(())
     }
 
+    /// 下载远程文件,先写入同目录下的临时文件,传输完成后再重命名,避免留下不完整的文件
     pub fn download(&self, local_path: &Path, remote_path: &Path) -> anyhow::Result<()> {
         info!("download {:?} to {:?}", remote_path, local_path);
-        let (mut channel, _stat) = self.session.scp_recv(remote_path)?;
-        let mut contents = Vec::new();
-        channel.read_to_end(&mut contents)?;
-
-        // Close the channel and wait for the whole content to be transferred
-        channel.send_eof()?;
-        channel.wait_eof()?;
-        channel.close()?;
-        channel.wait_close()?;
-        fs::write(local_path, contents)?;
+        let (size, mut reader) = self.reader(remote_path)?;
+        let dir = local_path
+            .parent()
+            .ok_or(anyhow!("invalid path {:?}", local_path))?;
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        let n = io::copy(&mut reader, &mut tmp)?;
+        if n != size {
+            bail!(
+                "download {:?} incomplete, {} of {} bytes",
+                remote_path,
+                n,
+                size
+            );
+        }
+        tmp.persist(local_path)?;
         Ok(())
     }
 
+    /// 打开远程文件,返回文件大小和读取流
+    pub fn reader(&self, remote_path: &Path) -> anyhow::Result<(u64, ScpReader)> {
+        let (channel, stat) = self.session.scp_recv(remote_path)?;
+        Ok((stat.size(), ScpReader { channel }))
+    }
+
     pub fn upload(&self, local_path: &Path, remote_file: &Path) -> anyhow::Result<()> {
         let size = local_path.metadata()?.len();
         info!("size {} upload {:?} to {:?}", size, local_path, remote_file);
-        let mut channel = self.session.scp_send(remote_file, 0o755, size, None)?;
-        channel.write_all(&fs::read(local_path)?)?;
+        self.write(remote_file, 0o755, size, &mut File::open(local_path)?)
+    }
 
-        // Close the channel and wait for the whole content to be transferred
-        channel.send_eof()?;
-        channel.wait_eof()?;
-        channel.close()?;
-        channel.wait_close()?;
-        Ok(())
+    /// 将size字节的流写入远程文件
+    pub fn write(
+        &self,
+        remote_file: &Path,
+        mode: i32,
+        size: u64,
+        data: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        let mut channel = self.session.scp_send(remote_file, mode, size, None)?;
+        let n = io::copy(&mut data.take(size), &mut channel)?;
+        if n != size {
+            bail!(
+                "upload {:?} incomplete, {} of {} bytes",
+                remote_file,
+                n,
+                size
+            );
+        }
+        Self::close(&mut channel)
     }
 
     pub fn write_remote_file(&self, content: &str, remote_path: &Path) -> anyhow::Result<()> {
         let size = content.len() as u64;
-        let mut channel = self.session.scp_send(remote_path, 0o644, size, None)?;
-        channel.write(content.as_bytes())?;
-        // Close the channel and wait for the whole content to be transferred
-        channel.send_eof()?;
-        channel.wait_eof()?;
-        channel.close()?;
-        channel.wait_close()?;
-        Ok(())
+        self.write(remote_path, 0o644, size, &mut content.as_bytes())
     }
 
     pub fn execute(&self, cmd: &str) -> anyhow::Result<String> {
+        Ok(self.execute_with_status(cmd)?.1)
+    }
+
+    /// 执行命令,返回退出码和标准输出
+    pub fn execute_with_status(&self, cmd: &str) -> anyhow::Result<(i32, String)> {
         let mut channel = self.session.channel_session()?;
         channel.exec(cmd)?;
         let mut s = String::new();
         channel.read_to_string(&mut s)?;
         channel.wait_close()?;
-        Ok(s)
+        Ok((channel.exit_status()?, s))
+    }
+
+    /// 执行命令,分别返回退出码、标准输出和标准错误
+    pub fn execute_with_stderr(&self, cmd: &str) -> anyhow::Result<(i32, String, String)> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(cmd)?;
+        Self::output(channel)
+    }
+
+    /// 执行命令并把input写入标准输入,分别返回退出码、标准输出和标准错误
+    pub fn execute_with_input(
+        &self,
+        cmd: &str,
+        input: &str,
+    ) -> anyhow::Result<(i32, String, String)> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(cmd)?;
+        channel.write_all(input.as_bytes())?;
+        channel.send_eof()?;
+        Self::output(channel)
+    }
+
+    /// 读完标准输出后再读标准错误,libssh2会缓存读取标准输出期间收到的标准错误
+    fn output(mut channel: Channel) -> anyhow::Result<(i32, String, String)> {
+        let mut out = String::new();
+        channel.read_to_string(&mut out)?;
+        let mut err = String::new();
+        channel.stderr().read_to_string(&mut err)?;
+        channel.wait_close()?;
+        Ok((channel.exit_status()?, out, err))
+    }
+
+    // Close the channel and wait for the whole content to be transferred
+    fn close(channel: &mut Channel) -> anyhow::Result<()> {
+        channel.send_eof()?;
+        channel.wait_eof()?;
+        channel.close()?;
+        channel.wait_close()?;
+        Ok(())
+    }
+}
+
+/// scp下载流,drop时关闭通道
+pub struct ScpReader {
+    channel: Channel,
+}
+
+impl Drop for ScpReader {
+    fn drop(&mut self) {
+        if let Err(e) = Agent::close(&mut self.channel) {
+            info!("close scp channel failed: {}", e);
+        }
+    }
+}
+
+impl Read for ScpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.channel.read(buf)
     }
 }
@@ -0,0 +1,189 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use md5::Digest;
+
+use crate::core::node::Meta;
+
+/// 块大小的下限、期望值和上限
+const MIN_CHUNK: usize = 256 * 1024;
+const AVG_CHUNK: usize = 1024 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// 期望值之前用更严格的掩码,之后用更宽松的掩码,使块大小集中在期望值附近。
+/// gear哈希左移,高位受更多字节影响,所以掩码取高位
+const MASK_S: u64 = ((1 << 22) - 1) << (64 - 22);
+const MASK_L: u64 = ((1 << 18) - 1) << (64 - 18);
+/// 每个字节对应的随机数,修改后已有文件的分块方式会改变,不能再复用已存储的块
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// FastCDC: 在MIN_CHUNK之后根据内容寻找切分点,文件中间插入或删除数据只影响附近的块
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let normal = AVG_CHUNK.min(end);
+    let mut hash = 0u64;
+    for (i, b) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        let mask = if i < normal { MASK_S } else { MASK_L };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// 按内容切分读取流,依次返回每一块的数据
+pub struct Chunker<R> {
+    inner: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(MAX_CHUNK),
+            eof: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 缓冲区不满MAX_CHUNK时只可能已到文件末尾,切分点与读取的方式无关
+        while !self.eof && self.buf.len() < MAX_CHUNK {
+            let want = (MAX_CHUNK - self.buf.len()) as u64;
+            match (&mut self.inner).take(want).read_to_end(&mut self.buf) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let n = cut(&self.buf);
+        let rest = self.buf.split_off(n);
+        Some(Ok(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// 切分文件,返回整个文件的摘要、文件权限和每一块的摘要
+pub fn split(path: &Path) -> anyhow::Result<Meta> {
+    let file = File::open(path)?;
+    let mode = file.metadata()?.permissions().mode();
+    let mut hasher = md5::Md5::default();
+    let mut chunks = Vec::new();
+    for chunk in Chunker::new(file) {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        chunks.push(format!("{:x}", md5::Md5::digest(&chunk)));
+    }
+    Ok(Meta::CHUNKED {
+        digest: format!("{:x}", hasher.finalize()),
+        mode,
+        chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 可重复的伪随机数据
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        Chunker::new(data).collect::<io::Result<_>>().unwrap()
+    }
+
+    /// 每次最多返回n字节的读取流
+    struct Slow<'a>(&'a [u8], usize);
+
+    impl Read for Slow<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(self.1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn cut_bounds() {
+        assert_eq!(cut(&[]), 0);
+        assert_eq!(cut(&[1; MIN_CHUNK]), MIN_CHUNK);
+        // 找不到切分点时在上限处切分
+        assert_eq!(cut(&vec![0; 2 * MAX_CHUNK]), MAX_CHUNK);
+        let data = random(2 * MAX_CHUNK, 1);
+        let n = cut(&data);
+        assert!(n > MIN_CHUNK && n <= MAX_CHUNK, "{}", n);
+        // 切分点只取决于前面的内容
+        assert_eq!(cut(&data[..n]), n);
+        assert_eq!(cut(&data[..MAX_CHUNK]), n);
+    }
+
+    #[test]
+    fn chunker_covers_input() {
+        assert!(chunks(&[]).is_empty());
+        let data = random(10 * AVG_CHUNK, 2);
+        let ans = chunks(&data);
+        assert_eq!(ans.concat(), data);
+        for chunk in &ans[..ans.len() - 1] {
+            assert!(chunk.len() > MIN_CHUNK && chunk.len() <= MAX_CHUNK);
+        }
+
+        // 切分结果与每次读取的长度无关
+        let slow: Vec<Vec<u8>> = Chunker::new(Slow(&data, 10_000))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(slow, ans);
+    }
+
+    #[test]
+    fn insert_only_changes_nearby_chunks() {
+        let data = random(12 * AVG_CHUNK, 3);
+        let before = chunks(&data);
+        let mut shifted = random(1000, 4);
+        shifted.extend(&data);
+        let after = chunks(&shifted);
+
+        // 开头插入数据后,之后的切分点随内容移动,其余的块保持不变
+        let reused = after.iter().filter(|c| before.contains(c)).count();
+        assert!(reused >= before.len() - 2, "{} of {}", reused, before.len());
+        assert_eq!(before.last(), after.last());
+    }
+}
@@ -1,15 +1,33 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::core::compress::Compression;
+use crate::core::host_key::HostKeyChecking;
+use crate::core::layout::Layout;
+use crate::core::sync::{Conflict, Overwrite};
+use crate::core::transport::TransportKind;
+use crate::core::util::{parse_rate, parse_size};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// output format of list, gc, index refs, remote ls, remote rm and remote gc
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// 命令的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     Add {
@@ -31,34 +49,486 @@ pub enum Commands {
 
     List {},
 
-    Info {},
+    Info {
+        /// also report the logical and physical size of all objects, reads every object
+        #[arg(long)]
+        sizes: bool,
+    },
+
+    /// show or change the settings of the local store
+    Config {
+        /// compression of objects written by add and received from remotes,
+        /// existing objects are kept as they are
+        #[arg(long, value_enum)]
+        compression: Option<Compression>,
+        /// split files of at least this size, such as 64M, into content-defined chunks
+        /// so that similar files share storage and transfers, added files only
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        chunk_threshold: Option<u64>,
+        /// store added files whole again
+        #[arg(long, conflicts_with = "chunk_threshold")]
+        no_chunking: bool,
+    },
+
+    /// move stored objects into another directory layout and keep using it for new objects
+    Migrate {
+        /// target layout, fanout spreads objects over 256 subdirectories for large stores
+        #[arg(long, value_enum)]
+        layout: Layout,
+    },
+
+    /// print entries as a manifest JSON document, all entries when no name is given
+    Export {
+        /// entry names
+        names: Vec<String>,
+        /// print the index of entry names to manifest digests instead of the entries
+        #[arg(long, conflicts_with = "names")]
+        index: bool,
+    },
+
+    /// replace all entries with a manifest JSON document printed by export
+    Import {
+        /// the manifest file, read from stdin when omitted
+        file: Option<PathBuf>,
+        /// only import when the current entries still have this digest,
+        /// used by remotes to update the config without overwriting concurrent changes
+        #[arg(long, value_name = "DIGEST")]
+        if_match: Option<String>,
+        /// replace the index with one printed by `export --index`,
+        /// the entry manifests it references must already be stored
+        #[arg(long)]
+        index: bool,
+    },
+
+    /// remove stored objects that no entry references
+    Gc {
+        /// only list the objects that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        gc: GcArgs,
+    },
 
     Pull {
-        /// ip or host , eg. root@127.0.0.1
+        /// user@host[:port], ssh://user@host:port, a host alias in ~/.ssh/config,
+        /// or the path of another hbx home such as /mnt/backup/.hbx or file:///mnt/backup/.hbx,
+        /// s3://bucket/prefix, http://host:port served by hbx serve, or a remote name
         address: String,
         /// package name ,split by ' '
         names: Vec<String>,
-        /// server port
-        #[arg(short)]
-        port: Option<String>,
         /// all tools, if -a/--all has set, will ignore names
         #[arg(short, long)]
         all: bool,
+        /// number of parallel transfer sessions
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
     },
 
     Push {
-        /// ip or host. eg. root@127.0.0.1
+        /// user@host[:port], ssh://user@host:port, a host alias in ~/.ssh/config,
+        /// or the path of another hbx home such as /mnt/backup/.hbx or file:///mnt/backup/.hbx,
+        /// s3://bucket/prefix, http://host:port served by hbx serve, or a remote name
         address: String,
         /// item names, split by space
         names: Vec<String>,
-        /// server port
-        #[arg(short, long)]
-        port: Option<String>,
-        /// if server not install hbx then install hbx
-        #[arg(short, long)]
-        install: bool,
+        #[command(flatten)]
+        install: InstallArgs,
         /// all tools, if -a/--all has set, will ignore names
         #[arg(short, long)]
         all: bool,
+        /// number of parallel transfer sessions
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+        /// remove objects no longer referenced on the remote after pushing
+        #[arg(long)]
+        prune: bool,
+        #[command(flatten)]
+        gc: GcArgs,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+    },
+
+    /// copy entries from one remote to another, streaming objects through this process
+    /// without storing them locally
+    Relay {
+        /// source remote name or address, in any form accepted by push and pull
+        src: String,
+        /// destination remote name or address
+        dst: String,
+        /// entry names, split by space, defaults to the entries of a named source remote
+        names: Vec<String>,
+        /// all entries of the source
+        #[arg(short, long)]
+        all: bool,
+        /// number of parallel transfer sessions to each remote
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        #[command(flatten)]
+        overwrite: OverwriteArgs,
+        #[command(flatten)]
+        encryption: RelayEncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
+    },
+
+    /// push entries changed locally and pull entries changed on the remote since the last sync
+    Sync {
+        /// remote name or address, in any form accepted by push and pull
+        address: String,
+        /// entry names, split by space, all entries by default
+        names: Vec<String>,
+        /// resolve entries changed on both sides, conflicts are only reported by default
+        #[arg(long, value_enum)]
+        conflict: Option<Conflict>,
+        /// number of parallel transfer sessions
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[command(flatten)]
+        transfer: TransferArgs,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
+    },
+
+    /// manage named remotes
+    Remote {
+        #[command(subcommand)]
+        command: RemoteCommands,
+    },
+
+    Serve {
+        /// address to listen on for http, such as 0.0.0.0:8080
+        #[arg(long, value_name = "ADDR")]
+        http: String,
+        /// token required for uploads, defaults to HBX_HTTP_TOKEN,
+        /// the store is read-only when no token is given
+        #[arg(long)]
+        token: Option<String>,
+    },
+
+    /// maintain the SQLite index of entries, nodes and object refcounts
+    #[cfg(feature = "sqlite")]
+    Index {
+        #[command(subcommand)]
+        command: IndexCommands,
+    },
+}
+
+/// SQLite索引的命令,需要编译时启用sqlite特性
+#[cfg(feature = "sqlite")]
+#[derive(Subcommand)]
+pub enum IndexCommands {
+    /// build the index from the JSON config, later changes to the config keep it up to date
+    Import {},
+    /// print indexed entries as a manifest JSON document, all entries when no name is given
+    Export {
+        /// entry names
+        names: Vec<String>,
+    },
+    /// list the entries and paths that reference a stored object
+    Refs {
+        /// object digest
+        digest: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RemoteCommands {
+    /// add a named remote, push and pull accept the name in place of the address
+    Add {
+        /// remote name
+        name: String,
+        /// address of the remote, in any form accepted by push and pull
+        url: String,
+        /// server port
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// private key file, can be given multiple times
+        #[arg(long = "identity", value_name = "FILE")]
+        identities: Vec<PathBuf>,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        /// path of hbx on the server
+        #[arg(long, value_name = "PATH")]
+        hbx_path: Option<String>,
+        /// entry synced when push or pull is given no names, can be given multiple times
+        #[arg(long = "entry", value_name = "NAME")]
+        entries: Vec<String>,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+    },
+
+    Remove {
+        /// remote name
+        name: String,
+    },
+
+    List {},
+
+    /// compare local entries with the remote
+    Show {
+        /// remote name
+        name: String,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
+    },
+
+    /// list entries stored on the remote
+    Ls {
+        /// remote name or address, in any form accepted by push and pull
+        remote: String,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
     },
+
+    /// delete entries from the remote
+    Rm {
+        /// remote name or address, in any form accepted by push and pull
+        remote: String,
+        /// entry names, split by space
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// remove objects no longer referenced on the remote afterwards
+        #[arg(long)]
+        prune: bool,
+        #[command(flatten)]
+        gc: GcArgs,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
+    },
+
+    /// remove objects that no entry references from the remote store
+    Gc {
+        /// remote name or address, in any form accepted by push and pull
+        name: String,
+        /// only list the objects that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        gc: GcArgs,
+        /// remote type, detected from the address by default
+        #[arg(long, value_enum)]
+        transport: Option<TransportKind>,
+        #[command(flatten)]
+        encryption: EncryptArgs,
+        #[command(flatten)]
+        ssh: SshArgs,
+        #[command(flatten)]
+        install: InstallArgs,
+    },
+}
+
+/// 清理时默认保留最近写入的未引用文件的秒数,HTTP服务的 `POST /gc` 使用相同的默认值
+pub const DEFAULT_GRACE: u64 = 3600;
+
+/// 清理未引用文件的参数
+#[derive(Args, Debug, Clone)]
+pub struct GcArgs {
+    /// keep unreferenced objects written within this many seconds,
+    /// they may belong to a push that has not updated the config yet
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_GRACE)]
+    pub grace: u64,
+}
+
+impl GcArgs {
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace)
+    }
+}
+
+/// push和pull替换已有条目的方式
+#[derive(Args, Debug, Clone, Default)]
+pub struct OverwriteArgs {
+    /// replace entries that differ even if the destination changed since the last sync
+    #[arg(long, conflicts_with = "no_overwrite")]
+    pub force: bool,
+    /// keep entries that already exist on the destination
+    #[arg(long)]
+    pub no_overwrite: bool,
+}
+
+impl OverwriteArgs {
+    pub fn mode(&self) -> Overwrite {
+        if self.force {
+            Overwrite::Force
+        } else if self.no_overwrite {
+            Overwrite::Never
+        } else {
+            Overwrite::Safe
+        }
+    }
+}
+
+/// ssh连接相关参数
+#[derive(Args, Debug, Clone, Default)]
+pub struct SshArgs {
+    /// server port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// private key file, can be given multiple times, tried after ssh-agent
+    #[arg(long = "identity", value_name = "FILE")]
+    pub identities: Vec<PathBuf>,
+    /// also try password and keyboard-interactive authentication,
+    /// the password is read from HBX_SSH_PASSWORD or prompted
+    #[arg(long)]
+    pub password: bool,
+    /// verify the server host key against ~/.ssh/known_hosts,
+    /// defaults to StrictHostKeyChecking in ~/.ssh/config or accept-new
+    #[arg(long, value_enum)]
+    pub host_key_checking: Option<HostKeyChecking>,
+    /// path of hbx on the server, skips looking it up
+    #[arg(long, value_name = "PATH")]
+    pub hbx_path: Option<String>,
+    /// enable ssh compression, helps on slow links with compressible files
+    #[arg(long)]
+    pub compress: bool,
+}
+
+/// 加密远程存储的参数
+#[derive(Args, Debug, Clone, Default)]
+pub struct EncryptArgs {
+    /// encrypt objects and the manifest on the remote, the key is derived from
+    /// the passphrase in HBX_ENCRYPTION_PASSPHRASE or prompted
+    #[arg(long)]
+    pub encrypt: bool,
+    /// derive the encryption key from the content of this file instead of a passphrase,
+    /// implies --encrypt
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptArgs {
+    pub fn enabled(&self) -> bool {
+        self.encrypt || self.key_file.is_some()
+    }
+}
+
+/// relay两端分别加密的参数,口令都从 HBX_ENCRYPTION_PASSPHRASE 读取或在终端中询问
+#[derive(Args, Debug, Clone, Default)]
+pub struct RelayEncryptArgs {
+    /// the source remote is encrypted
+    #[arg(long)]
+    pub src_encrypt: bool,
+    /// key file of the encrypted source remote, implies --src-encrypt
+    #[arg(long, value_name = "FILE")]
+    pub src_key_file: Option<PathBuf>,
+    /// encrypt objects and the manifest on the destination remote
+    #[arg(long)]
+    pub dst_encrypt: bool,
+    /// key file of the encrypted destination remote, implies --dst-encrypt
+    #[arg(long, value_name = "FILE")]
+    pub dst_key_file: Option<PathBuf>,
+}
+
+impl RelayEncryptArgs {
+    pub fn src(&self) -> EncryptArgs {
+        EncryptArgs {
+            encrypt: self.src_encrypt,
+            key_file: self.src_key_file.clone(),
+        }
+    }
+
+    pub fn dst(&self) -> EncryptArgs {
+        EncryptArgs {
+            encrypt: self.dst_encrypt,
+            key_file: self.dst_key_file.clone(),
+        }
+    }
+}
+
+/// 传输参数
+#[derive(Args, Debug, Clone, Default)]
+pub struct TransferArgs {
+    /// limit the total transfer rate in bytes per second, such as 500K, 2M or 1G
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+}
+
+/// 服务器未安装hbx时的安装参数
+#[derive(Args, Debug, Clone, Default)]
+pub struct InstallArgs {
+    /// if server not install hbx then install hbx
+    #[arg(short, long)]
+    pub install: bool,
+    /// install path on the server,
+    /// defaults to /usr/local/bin/hbx for root and ~/.local/bin/hbx for other users
+    #[arg(long, value_name = "PATH")]
+    pub install_path: Option<String>,
+    /// directory of cross-built binaries named hbx-<target>, <target>/hbx or <target>/release/hbx,
+    /// the local hbx is used when it matches the server platform
+    #[arg(long, value_name = "DIR")]
+    pub binaries: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overwrite(args: &[&str]) -> Result<Overwrite, clap::Error> {
+        let cli = Cli::try_parse_from(["hbx", "push", "host", "tool"].iter().chain(args))?;
+        match cli.command {
+            Commands::Push { overwrite, .. } => Ok(overwrite.mode()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn overwrite_flags() {
+        assert_eq!(overwrite(&[]).unwrap(), Overwrite::Safe);
+        assert_eq!(overwrite(&["--force"]).unwrap(), Overwrite::Force);
+        assert_eq!(overwrite(&["--no-overwrite"]).unwrap(), Overwrite::Never);
+        let err = overwrite(&["--force", "--no-overwrite"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
 }
@@ -0,0 +1,53 @@
+use std::io::{self, Cursor, Read, Write};
+
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// 压缩后的文件名后缀,文件仍以原始内容的摘要命名
+pub const ZSTD_SUFFIX: &str = ".zst";
+/// zstd帧头的最大长度
+const MAX_FRAME_HEADER: u64 = 18;
+
+/// 存储中文件的压缩方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// store objects as hard links to the added files
+    #[default]
+    None,
+    /// compress objects with zstd, add copies files instead of hard-linking them
+    Zstd,
+}
+
+/// 压缩size字节写入dst,帧头中记录原始大小,读取时不需要解压就能得到文件大小
+pub fn compress(data: &mut dyn Read, size: u64, dst: impl Write) -> anyhow::Result<()> {
+    let mut encoder = zstd::Encoder::new(dst, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    encoder.include_contentsize(true)?;
+    encoder.set_pledged_src_size(Some(size))?;
+    let n = io::copy(&mut data.take(size), &mut encoder)?;
+    if n != size {
+        bail!("compress incomplete, {} of {} bytes", n, size);
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+/// 从帧头读取原始大小,返回原始大小和解压后的读取流
+pub fn decompress<R: Read>(mut inner: R) -> anyhow::Result<(u64, impl Read)> {
+    let mut header = Vec::new();
+    (&mut inner)
+        .take(MAX_FRAME_HEADER)
+        .read_to_end(&mut header)?;
+    let size = content_size(&header)?;
+    let reader = Cursor::new(header).chain(inner);
+    Ok((size, zstd::Decoder::new(reader)?))
+}
+
+/// 帧头中记录的原始大小
+pub fn content_size(header: &[u8]) -> anyhow::Result<u64> {
+    zstd::zstd_safe::get_frame_content_size(header)
+        .ok()
+        .flatten()
+        .ok_or(anyhow!("missing content size in zstd frame header"))
+}
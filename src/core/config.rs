@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use anyhow::bail;
+use clap::ValueEnum;
+use md5::Digest;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+
+use crate::core::compress::Compression;
+use crate::core::layout::Layout;
+use crate::core::node::Node;
+use crate::core::remote::Remotes;
+use crate::FORMAT_VERSION;
+
+/// 文件摘要的算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+}
+
+/// 条目名称到条目清单摘要的索引
+pub type Index = BTreeMap<String, String>;
+
+/// 加密远程的条目索引,存储只用它计算gc时引用的文件,不会把它当作条目读取
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Sealed {
+    /// 派生密钥的盐
+    pub salt: String,
+    /// 加密后的条目索引
+    pub index: String,
+    /// 每个条目的引用列表,列出条目清单和文件的密文名称
+    pub refs: BTreeSet<String>,
+}
+
+impl Sealed {
+    /// 加密远程引用的所有文件: 加密的索引、引用列表和列表中的密文,read读取引用列表的内容
+    pub fn objects(
+        &self,
+        read: &dyn Fn(&str) -> anyhow::Result<String>,
+    ) -> anyhow::Result<HashSet<String>> {
+        let mut ans = HashSet::from([self.index.clone()]);
+        for refs in &self.refs {
+            let names: Vec<String> = from_str(&read(refs)?)?;
+            ans.extend(names);
+            ans.insert(refs.clone());
+        }
+        Ok(ans)
+    }
+}
+
+/// 存储的设置,与条目索引一起保存在配置文件中
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    #[serde(default)]
+    pub compression: Compression,
+    /// 不小于该大小的文件按内容切分存储,未设置时整个文件存储
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_threshold: Option<u64>,
+    /// 存储目录的布局,通过 `hbx migrate` 修改
+    #[serde(default)]
+    pub layout: Layout,
+}
+
+/// 配置文件: 格式版本、存储设置、条目索引和命名的远程存储,每个条目的清单作为文件保存在存储中
+#[derive(Debug, Deserialize, Serialize)]
+struct Document {
+    format_version: u32,
+    #[serde(default)]
+    settings: Settings,
+    index: Index,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed: Option<Sealed>,
+    #[serde(default, skip_serializing_if = "Remotes::is_empty")]
+    remotes: Remotes,
+}
+
+/// 只读取格式版本,判断是否能解析其余内容
+#[derive(Deserialize)]
+struct Version {
+    format_version: u32,
+}
+
+/// 基线版本的配置文件只有条目数组,没有格式版本
+fn is_baseline(content: &str) -> bool {
+    content.trim_start().starts_with('[')
+}
+
+/// 配置文件的格式版本,比当前hbx更新的格式返回错误
+fn version(content: &str) -> anyhow::Result<u32> {
+    if is_baseline(content) {
+        return Ok(1);
+    }
+    let version: Version = from_str(content)?;
+    if version.format_version > FORMAT_VERSION {
+        bail!(
+            "config uses format {}, hbx {} only supports formats up to {}, please upgrade hbx",
+            version.format_version,
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION
+        );
+    }
+    Ok(version.format_version)
+}
+
+/// 比当前格式旧的配置文件,需要转换为当前格式
+pub fn is_outdated(content: &str) -> anyhow::Result<bool> {
+    Ok(version(content)? < FORMAT_VERSION)
+}
+
+/// 解析基线版本的条目数组,其他旧格式不再支持
+pub fn parse_baseline(content: &str) -> anyhow::Result<HashSet<Node>> {
+    if !is_baseline(content) {
+        bail!(
+            "config uses format {}, hbx {} can't upgrade it",
+            version(content)?,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    Ok(from_str(content)?)
+}
+
+/// 解析保存索引的配置文件,返回存储设置、条目索引、加密远程的索引和命名的远程存储
+pub fn parse(content: &str) -> anyhow::Result<(Settings, Index, Option<Sealed>, Remotes)> {
+    let version = version(content)?;
+    if version < FORMAT_VERSION {
+        bail!("config uses format {}, it needs an upgrade", version);
+    }
+    let document: Document = from_str(content)?;
+    Ok((
+        document.settings,
+        document.index,
+        document.sealed,
+        document.remotes,
+    ))
+}
+
+/// 以当前格式序列化
+pub fn encode(
+    settings: &Settings,
+    index: &Index,
+    sealed: Option<&Sealed>,
+    remotes: &Remotes,
+) -> anyhow::Result<String> {
+    Ok(to_string(&Document {
+        format_version: FORMAT_VERSION,
+        settings: settings.clone(),
+        index: index.clone(),
+        sealed: sealed.cloned(),
+        remotes: remotes.clone(),
+    })?)
+}
+
+/// 条目清单的内容和摘要,内容相同的条目得到相同的摘要,不会重复保存
+pub fn manifest(node: &Node) -> anyhow::Result<(String, String)> {
+    let content = to_string(node)?;
+    let digest = format!("{:x}", md5::Md5::digest(content.as_bytes()));
+    Ok((digest, content))
+}
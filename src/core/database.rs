@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use serde_json::{from_str, to_string};
+
+use crate::core::config::Index;
+use crate::core::node::Meta::{CHUNKED, DIRECTORY, FILE, SYMLINK};
+use crate::core::node::Node;
+use crate::core::store::Store;
+use crate::INDEX_DATABASE_NAME;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    name TEXT PRIMARY KEY,
+    manifest TEXT NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS nodes (
+    entry TEXT NOT NULL,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    object TEXT
+);
+CREATE INDEX IF NOT EXISTS nodes_entry ON nodes (entry);
+CREATE INDEX IF NOT EXISTS nodes_object ON nodes (object);
+CREATE TABLE IF NOT EXISTS objects (
+    digest TEXT PRIMARY KEY,
+    refs INTEGER NOT NULL
+);
+";
+
+/// HBX_HOME中的SQLite索引: 条目、条目中的每个节点和文件的引用计数。
+/// 配置文件仍然是唯一的数据来源,索引按条目清单的摘要与配置比较,只更新变化的条目
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    /// 打开HBX_HOME中的索引,没有创建索引时返回None
+    pub fn open(home: &Path, timeout: Duration) -> anyhow::Result<Option<Self>> {
+        let path = home.join(INDEX_DATABASE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::connect(&path, timeout)?))
+    }
+
+    /// 删除已有的索引后重新创建
+    pub fn create(home: &Path, timeout: Duration) -> anyhow::Result<Self> {
+        let path = home.join(INDEX_DATABASE_NAME);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        Self::connect(&path, timeout)
+    }
+
+    fn connect(path: &Path, timeout: Duration) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(timeout)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// 与配置中的索引比较,删除和添加条目清单不同的条目。node根据条目清单的摘要读取条目
+    pub fn sync(
+        &mut self,
+        index: &Index,
+        node: &dyn Fn(&str) -> anyhow::Result<Node>,
+    ) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let existing: HashMap<String, String> = tx
+            .prepare("SELECT name, manifest FROM entries")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (name, manifest) in &existing {
+            if index.get(name) != Some(manifest) {
+                remove_entry(&tx, name, manifest)?;
+            }
+        }
+        for (name, manifest) in index {
+            if existing.get(name) != Some(manifest) {
+                add_entry(&tx, name, manifest, &node(manifest)?)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 被条目引用的文件,包括条目清单
+    pub fn referenced(&self) -> anyhow::Result<HashSet<String>> {
+        Ok(self
+            .conn
+            .prepare("SELECT digest FROM objects WHERE refs > 0")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// gc删除文件后移除没有引用的记录
+    pub fn forget(&mut self, digests: &[String]) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for digest in digests {
+            tx.execute(
+                "DELETE FROM objects WHERE digest = ?1 AND refs <= 0",
+                params![digest],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 引用文件的条目和文件在条目中的路径,条目清单的路径为空
+    pub fn refs(&self, digest: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let mut ans: Vec<(String, String)> = self
+            .conn
+            .prepare("SELECT DISTINCT entry, path FROM nodes WHERE object = ?1")?
+            .query_map(params![digest], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let manifest: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT name FROM entries WHERE manifest = ?1",
+                params![digest],
+                |row| row.get(0),
+            )
+            .optional()?;
+        ans.extend(manifest.map(|name| (name, String::new())));
+        ans.sort();
+        Ok(ans)
+    }
+
+    /// 所有条目,按条目清单的摘要索引
+    pub fn manifests(&self) -> anyhow::Result<HashMap<String, Node>> {
+        let contents: Vec<(String, String)> = self
+            .conn
+            .prepare("SELECT manifest, content FROM entries")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        contents
+            .into_iter()
+            .map(|(manifest, content)| Ok((manifest, from_str(&content)?)))
+            .collect()
+    }
+
+    /// 指定名称的条目,未指定名称时返回所有条目
+    pub fn entries(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        let contents: Vec<(String, String)> = self
+            .conn
+            .prepare("SELECT name, content FROM entries")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        contents
+            .into_iter()
+            .filter(|(name, _)| names.is_empty() || names.contains(name))
+            .map(|(_, content)| Ok(from_str(&content)?))
+            .collect()
+    }
+}
+
+/// 写入条目和其中的所有节点,条目引用的每个文件和条目清单的引用计数加一
+fn add_entry(tx: &Transaction, name: &str, manifest: &str, node: &Node) -> anyhow::Result<()> {
+    tx.execute(
+        "INSERT INTO entries (name, manifest, content) VALUES (?1, ?2, ?3)",
+        params![name, manifest, to_string(node)?],
+    )?;
+    let mut insert =
+        tx.prepare("INSERT INTO nodes (entry, path, kind, object) VALUES (?1, ?2, ?3, ?4)")?;
+    let mut stack = vec![(node.name.clone(), node)];
+    while let Some((path, node)) = stack.pop() {
+        match &node.meta {
+            FILE(digest) => {
+                insert.execute(params![name, path, "file", digest])?;
+            }
+            CHUNKED { chunks, .. } => {
+                insert.execute(params![name, path, "chunked", None::<String>])?;
+                for chunk in chunks {
+                    insert.execute(params![name, path, "chunk", chunk])?;
+                }
+            }
+            SYMLINK(_) => {
+                insert.execute(params![name, path, "symlink", None::<String>])?;
+            }
+            DIRECTORY(children) => {
+                insert.execute(params![name, path, "directory", None::<String>])?;
+                for child in children {
+                    stack.push((format!("{}/{}", path, child.name), child));
+                }
+            }
+        }
+    }
+    let mut objects = Store::get_files(&mut std::iter::once(node));
+    objects.insert(manifest.to_string());
+    for digest in objects {
+        tx.execute(
+            "INSERT INTO objects (digest, refs) VALUES (?1, 1)
+             ON CONFLICT (digest) DO UPDATE SET refs = refs + 1",
+            params![digest],
+        )?;
+    }
+    Ok(())
+}
+
+/// 删除条目和其中的节点,引用计数减一,计数为零的文件由gc删除
+fn remove_entry(tx: &Transaction, name: &str, manifest: &str) -> anyhow::Result<()> {
+    let mut objects: HashSet<String> = tx
+        .prepare("SELECT DISTINCT object FROM nodes WHERE entry = ?1 AND object IS NOT NULL")?
+        .query_map(params![name], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    objects.insert(manifest.to_string());
+    for digest in objects {
+        tx.execute(
+            "UPDATE objects SET refs = refs - 1 WHERE digest = ?1",
+            params![digest],
+        )?;
+    }
+    tx.execute("DELETE FROM nodes WHERE entry = ?1", params![name])?;
+    tx.execute("DELETE FROM entries WHERE name = ?1", params![name])?;
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use clap::ValueEnum;
+use dirs::home_dir;
+use log::info;
+use sha2::{Digest, Sha256};
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHosts, Session};
+
+use crate::core::address::Target;
+
+/// 服务器公钥的校验方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum HostKeyChecking {
+    /// only accept host keys already in known_hosts
+    Strict,
+    /// add unknown hosts to known_hosts, reject changed keys
+    #[default]
+    AcceptNew,
+    /// do not verify host keys
+    Off,
+}
+
+impl HostKeyChecking {
+    /// 对应 ~/.ssh/config 中 StrictHostKeyChecking 的取值
+    pub fn from_ssh_config(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "yes" | "ask" => Some(Self::Strict),
+            "accept-new" => Some(Self::AcceptNew),
+            "no" | "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// 在认证之前校验服务器公钥,防止中间人攻击
+pub fn verify(session: &Session, target: &Target) -> anyhow::Result<()> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or(anyhow!("server {} did not send a host key", target))?;
+    check(session, target, key, key_type, &known_hosts_path()?)
+}
+
+/// 按校验方式比较公钥与known_hosts,off时不校验,strict时拒绝未知主机,accept-new时追加新主机,公钥变化时总是拒绝
+fn check(
+    session: &Session,
+    target: &Target,
+    key: &[u8],
+    key_type: HostKeyType,
+    path: &Path,
+) -> anyhow::Result<()> {
+    if target.host_key_checking == HostKeyChecking::Off {
+        info!("host key checking is off for {}", target);
+        return Ok(());
+    }
+    let known = load(session, path)?;
+    let entry = if target.port == 22 {
+        target.host.clone()
+    } else {
+        format!("[{}]:{}", target.host, target.port)
+    };
+
+    match known.check_port(&target.host, target.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound if target.host_key_checking == HostKeyChecking::Strict => bail!(
+            "host key for {} is not in {:?} and strict checking is enabled\n{} key fingerprint is {}",
+            entry,
+            path,
+            type_name(key_type),
+            fingerprint(key)
+        ),
+        CheckResult::NotFound => {
+            info!(
+                "permanently added {} ({}) {} to {:?}",
+                entry,
+                type_name(key_type),
+                fingerprint(key),
+                path
+            );
+            if let Some(dir) = path.parent() {
+                create_dir_all(dir)?;
+            }
+            // 追加一行而不是用libssh2重写整个文件,保留文件中的注释和不支持的条目
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(f, "{} {} {}", entry, type_name(key_type), STANDARD.encode(key))?;
+            Ok(())
+        }
+        CheckResult::Mismatch => {
+            let stored = known
+                .hosts()?
+                .iter()
+                .filter(|h| h.name() == Some(entry.as_str()))
+                .filter_map(|h| STANDARD.decode(h.key()).ok())
+                .map(|k| fingerprint(&k))
+                .collect::<Vec<_>>();
+            bail!(
+                "WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED for {}!\n\
+                 someone could be eavesdropping on you right now (man-in-the-middle attack),\n\
+                 or the host key has just been changed.\n\
+                 offered {} key fingerprint: {}\n\
+                 known key fingerprint: {}\n\
+                 if the change is expected, remove the old entry from {:?}",
+                entry,
+                type_name(key_type),
+                fingerprint(key),
+                if stored.is_empty() {
+                    "(hashed entry)".to_string()
+                } else {
+                    stored.join(", ")
+                },
+                path
+            )
+        }
+        CheckResult::Failure => bail!("check host key of {} against {:?} failed", entry, path),
+    }
+}
+
+fn known_hosts_path() -> anyhow::Result<PathBuf> {
+    Ok(home_dir()
+        .ok_or(anyhow!("home directory not found"))?
+        .join(".ssh")
+        .join("known_hosts"))
+}
+
+/// 逐行读取,跳过libssh2不支持的条目(如@cert-authority和新的公钥类型)
+fn load(session: &Session, path: &Path) -> anyhow::Result<KnownHosts> {
+    let mut known = session.known_hosts()?;
+    if path.exists() {
+        for line in read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if known.read_str(line, KnownHostFileKind::OpenSSH).is_err() {
+                info!("skip unsupported known_hosts entry: {}", line);
+            }
+        }
+    }
+    Ok(known)
+}
+
+/// 与OpenSSH相同的SHA256指纹格式
+fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+fn type_name(key_type: HostKeyType) -> &'static str {
+    match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed255219 => "ssh-ed25519",
+        HostKeyType::Unknown => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"\0\0\0\x0bssh-ed25519\0\0\0\x20first host key for testing!!!!!";
+    const OTHER: &[u8] = b"\0\0\0\x0bssh-ed25519\0\0\0\x20other host key for testing!!!!!";
+
+    fn target(port: u16, host_key_checking: HostKeyChecking) -> Target {
+        Target {
+            user: "root".into(),
+            host: "example.com".into(),
+            port,
+            identity_files: Vec::new(),
+            host_key_checking,
+        }
+    }
+
+    fn verify(target: &Target, key: &[u8], path: &Path) -> anyhow::Result<()> {
+        check(
+            &Session::new().unwrap(),
+            target,
+            key,
+            HostKeyType::Ed255219,
+            path,
+        )
+    }
+
+    #[test]
+    fn ssh_config_values() {
+        assert_eq!(
+            HostKeyChecking::from_ssh_config("Yes"),
+            Some(HostKeyChecking::Strict)
+        );
+        assert_eq!(
+            HostKeyChecking::from_ssh_config("accept-new"),
+            Some(HostKeyChecking::AcceptNew)
+        );
+        assert_eq!(
+            HostKeyChecking::from_ssh_config("no"),
+            Some(HostKeyChecking::Off)
+        );
+        assert_eq!(HostKeyChecking::from_ssh_config("maybe"), None);
+    }
+
+    #[test]
+    fn strict_rejects_unknown_host() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("known_hosts");
+        let err = verify(&target(22, HostKeyChecking::Strict), KEY, &path).unwrap_err();
+        assert!(err.to_string().contains("strict checking"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn accept_new_adds_host() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".ssh").join("known_hosts");
+        let target = target(2222, HostKeyChecking::AcceptNew);
+        verify(&target, KEY, &path).unwrap();
+        let content = read_to_string(&path).unwrap();
+        assert!(content.starts_with("[example.com]:2222 ssh-ed25519 "));
+
+        // 添加之后strict也接受,公钥变化时拒绝
+        verify(&self::target(2222, HostKeyChecking::Strict), KEY, &path).unwrap();
+        let err = verify(&target, OTHER, &path).unwrap_err();
+        assert!(err.to_string().contains("HAS CHANGED"));
+        assert_eq!(read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn off_skips_checking() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("known_hosts");
+        std::fs::write(
+            &path,
+            format!("example.com ssh-ed25519 {}\n", STANDARD.encode(KEY)),
+        )
+        .unwrap();
+        verify(&target(22, HostKeyChecking::Off), OTHER, &path).unwrap();
+        verify(&target(2222, HostKeyChecking::Off), KEY, &path).unwrap();
+        assert_eq!(read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn skips_unsupported_entries() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("known_hosts");
+        std::fs::write(
+            &path,
+            format!(
+                "# comment\n@cert-authority * ssh-foo AAAA\nexample.com ssh-ed25519 {}\n",
+                STANDARD.encode(KEY)
+            ),
+        )
+        .unwrap();
+        verify(&target(22, HostKeyChecking::Strict), KEY, &path).unwrap();
+        assert!(verify(&target(22, HostKeyChecking::AcceptNew), OTHER, &path).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use log::info;
+
+use crate::core::agent::Agent;
+use crate::core::util::quote;
+
+/// 按顺序查找远程hbx: PATH中的hbx、用户目录和系统目录下的默认安装位置。
+/// 非交互式ssh会话的PATH通常不包含 ~/.local/bin,所以需要单独检查
+const FIND_SCRIPT: &str = r#"command -v hbx 2>/dev/null || for p in "$HOME/.local/bin/hbx" /usr/local/bin/hbx; do if [ -x "$p" ]; then echo "$p"; break; fi; done"#;
+
+/// 输出 `系统 架构 libc`,libc只对linux有意义
+const PLATFORM_SCRIPT: &str = r#"echo "$(uname -s) $(uname -m) $( (ldd --version 2>&1 | grep -qi musl || ls /lib/ld-musl-* >/dev/null 2>&1) && echo musl || echo gnu)""#;
+
+/// 服务器的系统和cpu架构
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub arch: String,
+    pub libc: String,
+}
+
+impl Platform {
+    /// 当前运行的hbx的平台
+    pub fn local() -> Self {
+        Self {
+            os: env::consts::OS.to_string(),
+            arch: env::consts::ARCH.to_string(),
+            libc: if cfg!(target_env = "musl") {
+                "musl"
+            } else {
+                "gnu"
+            }
+            .to_string(),
+        }
+    }
+
+    pub fn detect(agent: &Agent) -> anyhow::Result<Self> {
+        let (code, out) = agent.execute_with_status(PLATFORM_SCRIPT)?;
+        if code != 0 {
+            bail!("detect server platform failed: {}", out.trim());
+        }
+        Self::parse(&out)
+    }
+
+    /// 解析 [PLATFORM_SCRIPT] 的输出
+    fn parse(out: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = out.split_whitespace().collect();
+        if fields.len() != 3 {
+            bail!("detect server platform failed: {}", out.trim());
+        }
+        let os = match fields[0] {
+            "Linux" => "linux",
+            "Darwin" => "macos",
+            "FreeBSD" => "freebsd",
+            other => bail!("unsupported server os {}", other),
+        };
+        let arch = match fields[1] {
+            "x86_64" | "amd64" => "x86_64",
+            "aarch64" | "arm64" => "aarch64",
+            "armv7l" | "armv7" => "armv7",
+            "i686" | "i386" => "i686",
+            "riscv64" => "riscv64gc",
+            other => other,
+        };
+        Ok(Self {
+            os: os.to_string(),
+            arch: arch.to_string(),
+            libc: fields[2].to_string(),
+        })
+    }
+
+    /// rust target triple,按优先顺序排列。linux上静态链接的musl版本也可以在glibc系统上运行
+    pub fn targets(&self) -> Vec<String> {
+        match self.os.as_str() {
+            "linux" => {
+                let abi = |libc: &str| match self.arch.as_str() {
+                    "armv7" => format!("{}eabihf", libc),
+                    _ => libc.to_string(),
+                };
+                let mut ans = vec![format!("{}-unknown-linux-{}", self.arch, abi(&self.libc))];
+                if self.libc == "gnu" {
+                    ans.push(format!("{}-unknown-linux-{}", self.arch, abi("musl")));
+                }
+                ans
+            }
+            "macos" => vec![format!("{}-apple-darwin", self.arch)],
+            os => vec![format!("{}-unknown-{}", self.arch, os)],
+        }
+    }
+
+    /// 当前hbx是否可以直接在该平台运行
+    fn runs(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.arch == other.arch
+            && (self.libc == other.libc || self.libc == "musl")
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.targets()[0])
+    }
+}
+
+/// 查找服务器上的hbx,返回绝对路径
+pub fn find(agent: &Agent) -> anyhow::Result<Option<String>> {
+    let out = agent.execute(FIND_SCRIPT)?;
+    let path = out.trim();
+    Ok((!path.is_empty()).then(|| path.to_string()))
+}
+
+/// 安装与服务器平台匹配的hbx,返回安装路径。
+/// binaries目录中的文件命名为 `hbx-<target>`、`<target>/hbx` 或 `<target>/release/hbx`
+pub fn install(
+    agent: &Agent,
+    binaries: Option<&Path>,
+    install_path: Option<&str>,
+) -> anyhow::Result<String> {
+    let platform = Platform::detect(agent)?;
+    let binary = select_binary(&platform, binaries)?;
+
+    let path = match install_path {
+        Some(p) => p.to_string(),
+        None => default_install_path(agent)?,
+    };
+    info!(
+        "server install hbx for {} from {:?} to {}",
+        platform, binary, path
+    );
+
+    let dir = Path::new(&path)
+        .parent()
+        .ok_or(anyhow!("invalid install path {}", path))?
+        .to_string_lossy()
+        .to_string();
+    let (code, out) = agent.execute_with_status(&format!("mkdir -p {} 2>&1", quote(&dir)))?;
+    if code != 0 {
+        bail!("create {} on server failed: {}", dir, out.trim());
+    }
+    // 先上传到临时文件再重命名,升级正在运行的hbx时不会出现 text file busy
+    let tmp = format!("{}.tmp.{}", path, std::process::id());
+    agent.upload(&binary, Path::new(&tmp))?;
+    let (code, out) = agent.execute_with_status(&format!(
+        "chmod 755 {0} && mv -f {0} {1} 2>&1",
+        quote(&tmp),
+        quote(&path)
+    ))?;
+    if code != 0 {
+        bail!("install hbx to {} failed: {}", path, out.trim());
+    }
+    Ok(path)
+}
+
+fn select_binary(platform: &Platform, binaries: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let mut tried = Vec::new();
+    if let Some(dir) = binaries {
+        for target in platform.targets() {
+            for candidate in [
+                dir.join(format!("hbx-{}", target)),
+                dir.join(&target).join("hbx"),
+                dir.join(&target).join("release").join("hbx"),
+            ] {
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+                tried.push(candidate);
+            }
+        }
+    }
+    let local = Platform::local();
+    if local.runs(platform) {
+        return Ok(env::current_exe()?);
+    }
+    let mut msg = format!(
+        "no hbx binary for server platform {}, local hbx is built for {}",
+        platform, local
+    );
+    for p in tried {
+        msg.push_str(&format!("\n  not found: {}", p.display()));
+    }
+    bail!(
+        "{}\nuse --binaries to give a directory of cross-built binaries",
+        msg
+    )
+}
+
+/// root安装到 /usr/local/bin,普通用户安装到 ~/.local/bin
+fn default_install_path(agent: &Agent) -> anyhow::Result<String> {
+    user_install_path(&agent.execute("echo \"$(id -u) $HOME\"")?)
+}
+
+/// 根据 `<uid> <home>` 选择安装路径
+fn user_install_path(out: &str) -> anyhow::Result<String> {
+    match out.trim().split_once(' ') {
+        Some(("0", _)) => Ok("/usr/local/bin/hbx".to_string()),
+        Some((_, home)) if !home.is_empty() => Ok(format!("{}/.local/bin/hbx", home)),
+        _ => bail!("detect server user failed: {}", out.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn platform(os: &str, arch: &str, libc: &str) -> Platform {
+        Platform {
+            os: os.to_string(),
+            arch: arch.to_string(),
+            libc: libc.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_platform() {
+        assert_eq!(
+            Platform::parse("Linux x86_64 gnu\n").unwrap(),
+            platform("linux", "x86_64", "gnu")
+        );
+        assert_eq!(
+            Platform::parse("Darwin arm64 gnu").unwrap(),
+            platform("macos", "aarch64", "gnu")
+        );
+        assert_eq!(
+            Platform::parse("Linux armv7l musl").unwrap(),
+            platform("linux", "armv7", "musl")
+        );
+        assert_eq!(
+            Platform::parse("Linux riscv64 gnu").unwrap().arch,
+            "riscv64gc"
+        );
+        let err = Platform::parse("SunOS i86pc gnu").unwrap_err().to_string();
+        assert!(err.contains("unsupported server os SunOS"), "{}", err);
+        assert!(Platform::parse("sh: uname: not found").is_err());
+    }
+
+    #[test]
+    fn target_mapping() {
+        // glibc系统也可以运行静态链接的musl版本
+        assert_eq!(
+            platform("linux", "x86_64", "gnu").targets(),
+            ["x86_64-unknown-linux-gnu", "x86_64-unknown-linux-musl"]
+        );
+        assert_eq!(
+            platform("linux", "aarch64", "musl").targets(),
+            ["aarch64-unknown-linux-musl"]
+        );
+        assert_eq!(
+            platform("linux", "armv7", "gnu").targets(),
+            [
+                "armv7-unknown-linux-gnueabihf",
+                "armv7-unknown-linux-musleabihf"
+            ]
+        );
+        assert_eq!(
+            platform("macos", "aarch64", "gnu").targets(),
+            ["aarch64-apple-darwin"]
+        );
+        assert_eq!(
+            platform("freebsd", "x86_64", "gnu").targets(),
+            ["x86_64-unknown-freebsd"]
+        );
+        assert_eq!(
+            platform("linux", "x86_64", "gnu").to_string(),
+            "x86_64-unknown-linux-gnu"
+        );
+    }
+
+    #[test]
+    fn local_binary_runs() {
+        let gnu = platform("linux", "x86_64", "gnu");
+        let musl = platform("linux", "x86_64", "musl");
+        assert!(musl.runs(&gnu));
+        assert!(!gnu.runs(&musl));
+        assert!(gnu.runs(&gnu));
+        assert!(!gnu.runs(&platform("linux", "aarch64", "gnu")));
+        assert!(!gnu.runs(&platform("macos", "x86_64", "gnu")));
+    }
+
+    #[test]
+    fn binary_selection() {
+        let dir = TempDir::new().unwrap();
+        let server = platform("linux", "riscv64gc", "gnu");
+        let write = |path: PathBuf| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+            path
+        };
+
+        // 没有匹配的文件且本地hbx不能在服务器运行时列出查找过的路径
+        let err = select_binary(&server, Some(dir.path()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no hbx binary for server platform riscv64gc-unknown-linux-gnu"));
+        assert!(err.contains("hbx-riscv64gc-unknown-linux-musl"), "{}", err);
+        assert!(err.contains("--binaries"));
+
+        // musl版本作为glibc系统的备选
+        let musl = write(dir.path().join("riscv64gc-unknown-linux-musl/release/hbx"));
+        assert_eq!(select_binary(&server, Some(dir.path())).unwrap(), musl);
+        let gnu = write(dir.path().join("riscv64gc-unknown-linux-gnu/hbx"));
+        assert_eq!(select_binary(&server, Some(dir.path())).unwrap(), gnu);
+        let flat = write(dir.path().join("hbx-riscv64gc-unknown-linux-gnu"));
+        assert_eq!(select_binary(&server, Some(dir.path())).unwrap(), flat);
+
+        // 服务器与本地平台相同时使用当前的hbx
+        assert_eq!(
+            select_binary(&Platform::local(), None).unwrap(),
+            env::current_exe().unwrap()
+        );
+    }
+
+    #[test]
+    fn install_path() {
+        assert_eq!(
+            user_install_path("0 /root\n").unwrap(),
+            "/usr/local/bin/hbx"
+        );
+        assert_eq!(
+            user_install_path("1000 /home/u\n").unwrap(),
+            "/home/u/.local/bin/hbx"
+        );
+        assert!(user_install_path("1000 ").is_err());
+        assert!(user_install_path("").is_err());
+    }
+}
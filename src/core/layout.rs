@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// 存储目录中文件的布局
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// all objects directly in store/, named by digest
+    #[default]
+    Flat,
+    /// objects in 256 subdirectories named by the first two digest characters, store/ab/cdef...
+    Fanout,
+}
+
+impl Layout {
+    /// 文件相对存储目录的路径,name是摘要,压缩的文件带后缀
+    pub fn path(&self, name: &str) -> String {
+        match self {
+            Layout::Fanout if name.len() > 2 => format!("{}/{}", &name[..2], &name[2..]),
+            _ => name.to_string(),
+        }
+    }
+
+    /// fanout布局的子目录,由存储预先创建,ssh上传时不需要再创建目录
+    pub fn directories(&self) -> Vec<String> {
+        match self {
+            Layout::Flat => Vec::new(),
+            Layout::Fanout => (0..=255).map(|i| format!("{:02x}", i)).collect(),
+        }
+    }
+}
+
+/// 由相对存储目录的路径得到文件名,与布局无关
+pub fn name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect()
+}
@@ -0,0 +1,71 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use log::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// 读锁,可被多个进程同时持有
+    Shared,
+    /// 写锁,与其他任何锁互斥
+    Exclusive,
+}
+
+/// 基于文件的建议锁,drop时释放
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// 获取锁,超过timeout仍未获取则返回错误
+    pub fn acquire(path: &Path, mode: LockMode, timeout: Duration) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let res = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock(),
+            };
+            match res {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+            if start.elapsed() >= timeout {
+                bail!(
+                    "timeout after {:?} waiting for {:?} lock {:?}, another hbx process is using the store",
+                    timeout,
+                    mode,
+                    path
+                );
+            }
+            if !waiting {
+                info!("waiting for {:?} lock {:?}", mode, path);
+                waiting = true;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            info!("unlock {:?} failed: {}", self.path, e);
+        }
+    }
+}
@@ -1,5 +1,21 @@
+pub mod address;
 pub mod agent;
+pub mod auth;
+pub mod chunk;
 pub mod cli;
+pub mod compress;
+pub mod config;
+#[cfg(feature = "sqlite")]
+pub mod database;
+pub mod host_key;
+pub mod install;
+pub mod layout;
+pub mod lock;
 pub mod node;
+pub mod remote;
+pub mod server;
+pub mod ssh_config;
 pub mod store;
+pub mod sync;
+pub mod transport;
 pub mod util;
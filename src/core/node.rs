@@ -1,20 +1,25 @@
-use std::cell::RefCell;
 use std::fs::read_link;
 use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
 
 use anyhow::anyhow;
+use md5::Digest;
 use serde::{Deserialize, Serialize};
 
-use crate::core::node::Meta::{DIRECTORY, FILE, SYMLINK};
+use crate::core::node::Meta::{CHUNKED, DIRECTORY, FILE, SYMLINK};
 use crate::core::util::md5;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Meta {
     FILE(String),
     SYMLINK(PathBuf),
-    DIRECTORY(Rc<RefCell<Vec<Node>>>),
+    DIRECTORY(Vec<Node>),
+    /// 按内容切分存储的大文件,digest是整个文件的摘要,mode是文件权限,chunks是按顺序排列的每一块的摘要
+    CHUNKED {
+        digest: String,
+        mode: u32,
+        chunks: Vec<String>,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -51,7 +56,7 @@ impl TryFrom<&Path> for Node {
         let meta = if p.is_symlink() {
             SYMLINK(read_link(p)?)
         } else if p.is_dir() {
-            DIRECTORY(Rc::new(RefCell::new(Vec::new())))
+            DIRECTORY(Vec::new())
         } else {
             FILE(md5(p)?)
         };
@@ -78,10 +83,61 @@ impl Node {
         let meta = if p.is_symlink() {
             SYMLINK(p.read_link()?)
         } else if p.is_dir() {
-            DIRECTORY(Rc::new(RefCell::new(Vec::new())))
+            DIRECTORY(Vec::new())
         } else {
-            FILE(md5(&p)?)
+            FILE(md5(p)?)
         };
         Ok(Node { name, meta })
     }
+
+    /// 名称和内容都相同,子节点的顺序也相同。PartialEq只比较名称
+    pub fn same(&self, other: &Node) -> bool {
+        self.name == other.name
+            && match (&self.meta, &other.meta) {
+                (FILE(a), FILE(b)) => a == b,
+                (SYMLINK(a), SYMLINK(b)) => a == b,
+                (DIRECTORY(a), DIRECTORY(b)) => {
+                    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.same(y))
+                }
+                (
+                    CHUNKED {
+                        digest: a,
+                        mode: a_mode,
+                        chunks: a_chunks,
+                    },
+                    CHUNKED {
+                        digest: b,
+                        mode: b_mode,
+                        chunks: b_chunks,
+                    },
+                ) => a == b && a_mode == b_mode && a_chunks == b_chunks,
+                _ => false,
+            }
+    }
+
+    /// 节点内容的摘要,包含名称、文件内容、链接目标和目录结构,子节点按名称排序,与添加时的遍历顺序无关
+    pub fn digest(&self) -> String {
+        let mut hasher = md5::Md5::default();
+        self.feed(&mut hasher);
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn feed(&self, hasher: &mut md5::Md5) {
+        match &self.meta {
+            // 是否切分不影响内容摘要
+            FILE(digest) | CHUNKED { digest, .. } => {
+                hasher.update(format!("f {} {}\n", self.name, digest))
+            }
+            SYMLINK(target) => hasher.update(format!("l {} {}\n", self.name, target.display())),
+            DIRECTORY(children) => {
+                hasher.update(format!("d {}\n", self.name));
+                let mut children: Vec<&Node> = children.iter().collect();
+                children.sort_by(|a, b| a.name.cmp(&b.name));
+                for child in children {
+                    child.feed(hasher);
+                }
+                hasher.update("e\n");
+            }
+        }
+    }
 }
@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::core::cli::{EncryptArgs, SshArgs};
+use crate::core::node::Node;
+use crate::core::transport::TransportKind;
+
+/// 按名称保存的远程存储
+pub type Remotes = BTreeMap<String, Remote>;
+
+/// 命名的远程存储,保存地址和连接参数,push和pull时可以用名称代替地址
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Remote {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<TransportKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hbx_path: Option<String>,
+    /// 未指定名称时同步的条目
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<String>,
+    /// 远程中的文件和配置是否加密
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+}
+
+impl Remote {
+    /// 用远程的配置补充命令行参数,命令行中指定的参数优先
+    pub fn apply(
+        &self,
+        ssh: &mut SshArgs,
+        transport: &mut Option<TransportKind>,
+        encryption: &mut EncryptArgs,
+    ) {
+        ssh.port = ssh.port.or(self.port);
+        ssh.identities.extend(self.identities.iter().cloned());
+        ssh.hbx_path = ssh.hbx_path.take().or(self.hbx_path.clone());
+        *transport = transport.or(self.transport);
+        encryption.encrypt |= self.encrypt;
+        encryption.key_file = encryption.key_file.take().or(self.key_file.clone());
+    }
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)?;
+        if let Some(transport) = self.transport.and_then(|t| t.to_possible_value()) {
+            write!(f, " transport={}", transport.get_name())?;
+        }
+        if let Some(port) = self.port {
+            write!(f, " port={}", port)?;
+        }
+        for identity in &self.identities {
+            write!(f, " identity={}", identity.display())?;
+        }
+        if let Some(path) = &self.hbx_path {
+            write!(f, " hbx-path={}", path)?;
+        }
+        if !self.entries.is_empty() {
+            write!(f, " entries={}", self.entries.join(","))?;
+        }
+        if self.encrypt {
+            write!(f, " encrypted")?;
+        }
+        if let Some(path) = &self.key_file {
+            write!(f, " key-file={}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// 地址是已配置的远程名称时返回其地址,并用远程的配置补充参数,
+/// 未指定条目且没有 -a/--all 时使用远程的默认条目
+pub fn resolve(
+    remotes: &Remotes,
+    address: &str,
+    names: &mut Vec<String>,
+    all: bool,
+    ssh: &mut SshArgs,
+    transport: &mut Option<TransportKind>,
+    encryption: &mut EncryptArgs,
+) -> String {
+    match remotes.get(address) {
+        Some(remote) => {
+            remote.apply(ssh, transport, encryption);
+            if names.is_empty() && !all {
+                names.extend(remote.entries.iter().cloned());
+            }
+            remote.url.clone()
+        }
+        None => address.to_string(),
+    }
+}
+
+/// 本地和远程条目的比较结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// 内容相同
+    UpToDate,
+    /// 只有本地有
+    Ahead,
+    /// 只有远程有
+    Behind,
+    /// 两边都有但内容不同
+    Diverged,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Status::UpToDate => "up-to-date",
+            Status::Ahead => "ahead",
+            Status::Behind => "behind",
+            Status::Diverged => "diverged",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 按条目名称比较本地和远程配置的内容摘要
+pub fn compare(local: &HashSet<Node>, remote: &HashSet<Node>) -> BTreeMap<String, Status> {
+    let mut ans = BTreeMap::new();
+    for node in local {
+        let status = match remote.get(node) {
+            None => Status::Ahead,
+            Some(other) if other.digest() == node.digest() => Status::UpToDate,
+            Some(_) => Status::Diverged,
+        };
+        ans.insert(node.name.clone(), status);
+    }
+    for node in remote.difference(local) {
+        ans.insert(node.name.clone(), Status::Behind);
+    }
+    ans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::node::Meta;
+
+    fn file(name: &str, digest: &str) -> Node {
+        Node {
+            name: name.into(),
+            meta: Meta::FILE(digest.into()),
+        }
+    }
+
+    #[test]
+    fn compare_entries() {
+        let local = HashSet::from([file("same", "a"), file("changed", "b"), file("new", "c")]);
+        let remote = HashSet::from([file("same", "a"), file("changed", "x"), file("old", "d")]);
+        let ans: Vec<_> = compare(&local, &remote).into_iter().collect();
+        assert_eq!(
+            ans,
+            [
+                ("changed".to_string(), Status::Diverged),
+                ("new".to_string(), Status::Ahead),
+                ("old".to_string(), Status::Behind),
+                ("same".to_string(), Status::UpToDate),
+            ]
+        );
+        assert!(compare(&HashSet::new(), &HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn resolve_named_remote() {
+        let remotes = Remotes::from([(
+            "nas".to_string(),
+            Remote {
+                url: "root@nas".into(),
+                port: Some(2222),
+                entries: vec!["tool".into()],
+                encrypt: true,
+                ..Default::default()
+            },
+        )]);
+        let (mut ssh, mut transport, mut encryption) =
+            (SshArgs::default(), None, EncryptArgs::default());
+        let mut names = Vec::new();
+        let address = resolve(
+            &remotes,
+            "nas",
+            &mut names,
+            false,
+            &mut ssh,
+            &mut transport,
+            &mut encryption,
+        );
+        assert_eq!(address, "root@nas");
+        assert_eq!(names, ["tool"]);
+        assert_eq!(ssh.port, Some(2222));
+        assert!(encryption.encrypt);
+
+        // 命令行参数优先,-a时不使用默认条目
+        let mut ssh = SshArgs {
+            port: Some(22),
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        resolve(
+            &remotes,
+            "nas",
+            &mut names,
+            true,
+            &mut ssh,
+            &mut transport,
+            &mut encryption,
+        );
+        assert_eq!(ssh.port, Some(22));
+        assert!(names.is_empty());
+
+        // 不是远程名称时原样返回地址
+        let mut names = Vec::new();
+        let address = resolve(
+            &remotes,
+            "root@other",
+            &mut names,
+            false,
+            &mut ssh,
+            &mut transport,
+            &mut encryption,
+        );
+        assert_eq!(address, "root@other");
+        assert!(names.is_empty());
+    }
+}
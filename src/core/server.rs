@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::info;
+use md5::Digest;
+use tempfile::NamedTempFile;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::core::cli::DEFAULT_GRACE;
+use crate::core::compress::{Compression, ZSTD_SUFFIX};
+use crate::core::node::Node;
+use crate::core::store::Store;
+use crate::core::transport::manifest;
+
+/// md5摘要的十六进制长度,其他长度的名称是加密远程的密文
+const MD5_LEN: usize = 32;
+/// 同时处理请求的线程数
+const WORKERS: usize = 8;
+
+type HttpResponse = Response<Box<dyn Read + Send>>;
+
+/// 通过http提供存储,未设置token时只读,设置后写入请求需携带 `Authorization: Bearer <token>`。
+///
+/// - `GET /manifest` 读取配置,`PUT /manifest` 配合 `If-Match` 条件写入,没有 `If-Match` 时无条件写入
+/// - `GET /index` 读取条目名称到条目清单摘要的索引,条目清单通过 `/objects/<digest>` 下载,
+///   `PUT /index` 配合 `If-Match` 条件替换索引,索引引用的条目清单需已上传
+/// - `HEAD /objects/<digest>` 检查文件是否存在
+/// - `GET /objects/<digest>` 下载文件,支持 `Range` 断点续传
+/// - `PUT /objects/<digest>` 上传文件
+/// - `POST /gc?grace=<seconds>&dry_run=<bool>` 删除没有被配置引用的文件,返回删除的文件摘要和大小,grace默认3600秒
+pub fn serve(path: &Path, addr: &str, token: Option<String>) -> anyhow::Result<()> {
+    let server = Server::http(addr).map_err(|e| anyhow!("listen on {} failed: {}", addr, e))?;
+    info!(
+        "serve {:?} on http://{} {}",
+        path,
+        server.server_addr(),
+        if token.is_some() {
+            "read-write"
+        } else {
+            "read-only"
+        }
+    );
+    thread::scope(|s| {
+        for _ in 0..WORKERS {
+            s.spawn(|| {
+                while let Ok(req) = server.recv() {
+                    handle(path, token.as_deref(), req);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(path: &Path, token: Option<&str>, mut req: Request) {
+    let method = req.method().clone();
+    let url = req.url().to_string();
+    let res = match route(path, token, &mut req) {
+        Ok(res) => res,
+        Err(e) => text(500, &e.to_string()),
+    };
+    info!("{} {} {}", method, url, res.status_code().0);
+    if let Err(e) = req.respond(res) {
+        info!("send response of {} {} failed: {}", method, url, e);
+    }
+}
+
+fn route(path: &Path, token: Option<&str>, req: &mut Request) -> anyhow::Result<HttpResponse> {
+    let url = req.url().to_string();
+    let (url, query) = url.split_once('?').unwrap_or((&url, ""));
+    let writing = matches!(req.method(), Method::Put | Method::Post);
+    if writing {
+        match token {
+            None => return Ok(text(403, "server is read-only")),
+            Some(t) if !token_matches(header(req, "Authorization").as_deref(), t) => {
+                return Ok(text(401, "invalid token"))
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut store = Store::new(path.to_path_buf())?;
+    match (req.method(), url) {
+        (Method::Get, "/manifest") => {
+            store.load()?;
+            let body = manifest::encode(store.data())?;
+            Ok(bytes(200, body.into_bytes())
+                .with_header(make_header("ETag", &etag(store.data())?))
+                .with_header(make_header("Content-Type", "application/json")))
+        }
+        (Method::Get, "/index") => {
+            let index = store.remote_index()?;
+            let body = manifest::encode_index(&index)?;
+            Ok(bytes(200, body.into_bytes())
+                .with_header(make_header(
+                    "ETag",
+                    &format!("\"{}\"", manifest::index_etag(&index)?),
+                ))
+                .with_header(make_header("Content-Type", "application/json")))
+        }
+        (Method::Put, "/manifest") => put_manifest(&mut store, req),
+        (Method::Put, "/index") => put_index(&mut store, req),
+        (Method::Post, "/gc") => gc(&mut store, query),
+        (method, _) => match url.strip_prefix("/objects/") {
+            Some(digest) if !is_digest(digest) => Ok(text(400, "invalid digest")),
+            Some(digest) => {
+                let digest = digest.to_string();
+                match method {
+                    Method::Head | Method::Get => get_object(&store, &digest, req),
+                    Method::Put => put_object(&store, &digest, req),
+                    _ => Ok(text(405, "method not allowed")),
+                }
+            }
+            None => Ok(text(404, "not found")),
+        },
+    }
+}
+
+/// 比较耗时只与长度有关,不会从响应时间逐字节猜出token
+fn token_matches(authorization: Option<&str>, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    let Some(given) = authorization else {
+        return false;
+    };
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 有 `If-Match` 时在配置写锁内比较ETag,与客户端读取时的配置相同才写入,没有时直接写入
+fn put_manifest(store: &mut Store, req: &mut Request) -> anyhow::Result<HttpResponse> {
+    let expected = header(req, "If-Match");
+    let mut content = String::new();
+    req.as_reader().read_to_string(&mut content)?;
+    let new = manifest::decode(&content)?;
+    let expected = expected.as_deref().map(|e| e.trim_matches('"'));
+    Ok(if store.import(new, expected)? {
+        text(200, "ok")
+    } else {
+        text(412, manifest::CONFLICT)
+    })
+}
+
+/// 与 `PUT /manifest` 相同,有 `If-Match` 时在配置写锁内比较索引的ETag
+fn put_index(store: &mut Store, req: &mut Request) -> anyhow::Result<HttpResponse> {
+    let expected = header(req, "If-Match");
+    let mut content = String::new();
+    req.as_reader().read_to_string(&mut content)?;
+    let index = manifest::decode_index(&content)?;
+    let expected = expected.as_deref().map(|e| e.trim_matches('"'));
+    Ok(if store.swap_index(&index, expected)? {
+        text(200, "ok")
+    } else {
+        text(412, manifest::CONFLICT)
+    })
+}
+
+/// grace缺省时与 `hbx gc` 相同,保留最近写入的文件,只列出不删除需要 `dry_run=true`
+fn gc(store: &mut Store, query: &str) -> anyhow::Result<HttpResponse> {
+    let mut grace = DEFAULT_GRACE;
+    let mut dry_run = false;
+    for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+        let valid = match key {
+            "grace" => value.parse().map(|v| grace = v).is_ok(),
+            "dry_run" => value.parse().map(|v| dry_run = v).is_ok(),
+            _ => true,
+        };
+        if !valid {
+            return Ok(text(400, &format!("invalid {}", key)));
+        }
+    }
+    let removed = store.gc(dry_run, Duration::from_secs(grace))?;
+    Ok(bytes(200, serde_json::to_vec(&removed)?)
+        .with_header(make_header("Content-Type", "application/json")))
+}
+
+/// 文件名即内容摘要,直接作为ETag。压缩的文件解压后返回
+fn get_object(store: &Store, digest: &str, req: &Request) -> anyhow::Result<HttpResponse> {
+    let Some(path) = store.locate(digest) else {
+        return Ok(text(404, "object not found"));
+    };
+    let file = if path.to_string_lossy().ends_with(ZSTD_SUFFIX) {
+        None
+    } else {
+        Some(File::open(&path)?)
+    };
+    let size = match &file {
+        Some(f) => f.metadata()?.len(),
+        None => store.read_object(digest)?.0,
+    };
+    let etag = format!("\"{}\"", digest);
+    let if_range = header(req, "If-Range");
+    // 文件在续传期间变化(If-Range不匹配)或Range格式不支持时返回完整文件
+    let start = match header(req, "Range").and_then(|r| parse_range(&r)) {
+        Some(_) if size == 0 || (if_range.is_some() && if_range.as_ref() != Some(&etag)) => None,
+        Some(start) if start >= size => {
+            return Ok(text(416, "range not satisfiable")
+                .with_header(make_header("Content-Range", &format!("bytes */{}", size))))
+        }
+        start => start,
+    };
+
+    let (code, start) = match start {
+        Some(start) => (206, start),
+        None => (200, 0),
+    };
+    let reader: Box<dyn Read + Send> = match file {
+        Some(mut f) => {
+            f.seek(SeekFrom::Start(start))?;
+            Box::new(f)
+        }
+        None => {
+            // 解压流不能seek,跳过start之前的内容
+            let (_, mut reader) = store.read_object(digest)?;
+            io::copy(&mut (&mut reader).take(start), &mut io::sink())?;
+            reader
+        }
+    };
+    let mut res = Response::new(
+        StatusCode(code),
+        vec![
+            make_header("ETag", &etag),
+            make_header("Accept-Ranges", "bytes"),
+            make_header("Content-Type", "application/octet-stream"),
+        ],
+        reader,
+        Some((size - start) as usize),
+        None,
+    )
+    // 大文件默认使用chunked编码,客户端需要Content-Length校验文件大小
+    .with_chunked_threshold(usize::MAX);
+    if code == 206 {
+        res = res.with_header(make_header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, size - 1, size),
+        ));
+    }
+    Ok(res)
+}
+
+/// 写入前持有gc读锁,写入的内容与摘要不一致时丢弃。
+/// 加密远程的密文以带密钥的摘要命名,服务器无法校验,只校验大小
+fn put_object(store: &Store, digest: &str, req: &mut Request) -> anyhow::Result<HttpResponse> {
+    let size = match req.body_length() {
+        Some(size) => size as u64,
+        None => return Ok(text(411, "content length required")),
+    };
+    let _gc = store.gc_lock()?;
+    let mut reader = HashReader {
+        inner: req.as_reader(),
+        hasher: md5::Md5::default(),
+    };
+    let mut tmp = NamedTempFile::new_in(store.store_dir())?;
+    let n = io::copy(&mut (&mut reader).take(size), &mut tmp)?;
+    let actual = format!("{:x}", reader.hasher.finalize());
+    if n != size || (digest.len() == MD5_LEN && actual != digest) {
+        return Ok(text(
+            400,
+            &format!("content digest {} does not match {}", actual, digest),
+        ));
+    }
+    match store.settings().compression {
+        Compression::None => {
+            Store::persist(tmp, &store.object_path(digest))?;
+        }
+        Compression::Zstd => {
+            let mut file = tmp.reopen()?;
+            store.write_object(digest, size, &mut file)?;
+        }
+    }
+    Ok(text(201, "created"))
+}
+
+/// 读取时计算md5
+struct HashReader<R> {
+    inner: R,
+    hasher: md5::Md5,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn etag(data: &HashSet<Node>) -> anyhow::Result<String> {
+    Ok(format!("\"{}\"", manifest::etag(data)?))
+}
+
+/// 只支持断点续传需要的 `bytes=N-`,返回起始位置
+fn parse_range(range: &str) -> Option<u64> {
+    let start = range.strip_prefix("bytes=")?.strip_suffix('-')?;
+    start.trim().parse().ok()
+}
+
+fn is_digest(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn header(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.to_string())
+}
+
+fn make_header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn bytes(code: u16, body: Vec<u8>) -> HttpResponse {
+    let len = body.len();
+    Response::new(
+        StatusCode(code),
+        vec![],
+        Box::new(Cursor::new(body)),
+        Some(len),
+        None,
+    )
+}
+
+fn text(code: u16, msg: &str) -> HttpResponse {
+    bytes(code, msg.as_bytes().to_vec())
+}
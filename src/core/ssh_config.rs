@@ -0,0 +1,221 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use dirs::home_dir;
+use log::info;
+
+/// ~/.ssh/config 中某个Host解析出的配置,按ssh的规则第一次出现的值生效
+#[derive(Debug, Default, Clone)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub strict_host_key_checking: Option<String>,
+}
+
+impl HostConfig {
+    /// 读取 ~/.ssh/config,文件不存在时返回空配置
+    pub fn resolve(alias: &str) -> anyhow::Result<Self> {
+        let path = match home_dir() {
+            None => return Ok(Self::default()),
+            Some(home) => home.join(".ssh").join("config"),
+        };
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = read_to_string(&path)?;
+        Self::parse(&content, alias).map_err(|e| anyhow!("{:?}: {}", path, e))
+    }
+
+    pub fn parse(content: &str, alias: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        // 文件开头Host之前的配置对所有主机生效
+        let mut matched = true;
+        for (no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // 与ssh一样只检查生效的配置,其他Host块中无法解析的行跳过
+            let Some((key, value)) = split_line(line) else {
+                if matched {
+                    bail!("line {}: invalid option '{}'", no + 1, line);
+                }
+                info!(
+                    "ssh config line {}: ignore invalid option '{}'",
+                    no + 1,
+                    line
+                );
+                continue;
+            };
+            match key.to_lowercase().as_str() {
+                "host" => matched = host_matches(value, alias),
+                // Match块的条件不做解析,视为不匹配
+                "match" => matched = false,
+                "include" => info!("ssh config Include is not supported, ignore '{}'", value),
+                _ if !matched => {}
+                "hostname" => {
+                    config
+                        .hostname
+                        .get_or_insert_with(|| value.replace("%h", alias));
+                }
+                "user" => {
+                    config.user.get_or_insert_with(|| value.to_string());
+                }
+                "port" if config.port.is_none() => {
+                    let port = value
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: invalid port '{}'", no + 1, value))?;
+                    config.port = Some(port);
+                }
+                "identityfile" => config.identity_files.push(expand_tilde(value)),
+                "stricthostkeychecking" => {
+                    config
+                        .strict_host_key_checking
+                        .get_or_insert_with(|| value.to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// 支持 `Key value` 和 `Key=value` 两种写法,值可以用双引号包裹
+fn split_line(line: &str) -> Option<(&str, &str)> {
+    let idx = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let key = &line[..idx];
+    let value = line[idx..]
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+        .trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    if key.is_empty() || value.is_empty() {
+        return None;
+    }
+    Some((key, value))
+}
+
+/// Host行包含多个以空格分隔的模式,`!`开头的模式匹配时整行不匹配
+fn host_matches(patterns: &str, alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        match pattern.strip_prefix('!') {
+            Some(p) if wildcard(p.as_bytes(), alias.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= wildcard(pattern.as_bytes(), alias.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// 支持 `*` 和 `?` 通配符
+fn wildcard(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard(&pattern[1..], s) || (!s.is_empty() && wildcard(pattern, &s[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &s[1..]),
+        (Some(p), Some(c)) if p.eq_ignore_ascii_case(c) => wildcard(&pattern[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => Path::new(path).to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+# 全局配置
+StrictHostKeyChecking no
+
+Host prod web-*
+    HostName %h.example.com
+    User deploy
+    Port=2222
+    IdentityFile ~/.ssh/deploy
+    IdentityFile \"/etc/keys/shared key\"
+
+Host * !bastion
+    User fallback
+    Port 22
+    IdentityFile ~/.ssh/id_ed25519
+
+Match host bastion
+    User ignored
+";
+
+    #[test]
+    fn first_value_wins() {
+        let config = HostConfig::parse(CONFIG, "prod").unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("prod.example.com"));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(config.strict_host_key_checking.as_deref(), Some("no"));
+        // IdentityFile可以出现多次,按顺序全部保留
+        assert_eq!(
+            config.identity_files,
+            vec![
+                expand_tilde("~/.ssh/deploy"),
+                PathBuf::from("/etc/keys/shared key"),
+                expand_tilde("~/.ssh/id_ed25519"),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcards_and_negation() {
+        let config = HostConfig::parse(CONFIG, "web-01").unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("web-01.example.com"));
+
+        let config = HostConfig::parse(CONFIG, "other").unwrap();
+        assert_eq!(config.hostname, None);
+        assert_eq!(config.user.as_deref(), Some("fallback"));
+
+        // 被 `!bastion` 排除,Match块不解析
+        let config = HostConfig::parse(CONFIG, "bastion").unwrap();
+        assert_eq!(config.user, None);
+        assert_eq!(config.port, None);
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(wildcard(b"web-??", b"WEB-01"));
+        assert!(!wildcard(b"web-??", b"web-1"));
+        assert!(wildcard(b"*.example.com", b"a.b.example.com"));
+        assert!(!wildcard(b"*.example.com", b"example.com"));
+        assert!(host_matches("a b", "b"));
+        assert!(!host_matches("* !b", "b"));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(HostConfig::parse("Port", "h").is_err());
+        assert!(HostConfig::parse("Host h\nPort abc", "h").is_err());
+        // 不匹配的Host中的错误端口和无法解析的行被跳过
+        assert!(HostConfig::parse("Host other\nPort abc", "h").is_ok());
+        let config = HostConfig::parse("Host other\nBroken\nHost h\nUser u", "h").unwrap();
+        assert_eq!(config.user.as_deref(), Some("u"));
+        assert!(HostConfig::parse("Host h\nBroken", "h").is_err());
+    }
+
+    #[test]
+    fn tilde_expansion() {
+        let home = home_dir().unwrap();
+        assert_eq!(expand_tilde("~/.ssh/id_rsa"), home.join(".ssh/id_rsa"));
+        assert_eq!(expand_tilde("/abs/key"), PathBuf::from("/abs/key"));
+        assert_eq!(expand_tilde("~user/key"), PathBuf::from("~user/key"));
+    }
+}
@@ -1,41 +1,119 @@
-use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir_all, hard_link, read_to_string};
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{create_dir_all, hard_link, read_to_string, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{env, fs};
 
 use anyhow::{anyhow, bail};
 use atomicwrites::{AllowOverwrite, AtomicFile};
+use clap::ValueEnum;
 use dirs::home_dir;
 use log::info;
+use md5::Digest;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
+use serde_json::{from_str, to_string, to_string_pretty};
+use tempfile::NamedTempFile;
 
-use crate::core::agent::Agent;
-use crate::core::node::Meta::{DIRECTORY, FILE, SYMLINK};
+use crate::core::chunk::{self, Chunker};
+use crate::core::compress::{self, Compression, ZSTD_SUFFIX};
+use crate::core::config::{self, Index, Sealed, Settings};
+#[cfg(feature = "sqlite")]
+use crate::core::database::Database;
+use crate::core::layout::{self, Layout};
+use crate::core::lock::{FileLock, LockMode};
+use crate::core::node::Meta::{CHUNKED, DIRECTORY, FILE, SYMLINK};
 use crate::core::node::Node;
-use crate::{CONFIG_NAME, HBX_HOME_ENV, STORE_DIRECTORY};
+use crate::core::remote::Remotes;
+use crate::core::sync::{self, Action, Change, Conflict, Overwrite, SyncBase, SyncState};
+use crate::core::transport::manifest::{self, RemoteIndex};
+use crate::core::transport::Transport;
+use crate::core::util::{parallel, Meter};
+use crate::{
+    CONFIG_BACKUP_NAME, CONFIG_LOCK_NAME, CONFIG_NAME, FORMAT_VERSION, GC_LOCK_NAME, HBX_HOME_ENV,
+    HBX_LOCK_TIMEOUT_ENV, STORE_DIRECTORY, SYNC_STATE_NAME,
+};
+
+/// 默认锁等待时间
+const DEFAULT_LOCK_TIMEOUT: u64 = 60;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Store {
     path: PathBuf,
     data: HashSet<Node>,
+    /// 与条目索引一起从配置文件读取
+    #[serde(skip)]
+    settings: Settings,
+    /// 条目名称到条目清单摘要的索引,条目清单由load读取
+    #[serde(skip)]
+    index: Index,
+    /// 作为加密远程时保存的加密索引,与索引一起读取
+    #[serde(skip)]
+    sealed: Option<Sealed>,
+    /// 命名的远程存储,与索引一起读取
+    #[serde(skip)]
+    remotes: Remotes,
+    /// 已读取或写入的条目清单,按清单摘要保存。重新加载时不再读取,保存时内容不变的条目沿用原来的摘要
+    #[serde(skip)]
+    manifests: HashMap<String, Node>,
 }
 
 impl Store {
     pub fn new(path: PathBuf) -> anyhow::Result<Self> {
         create_dir_all(path.join(STORE_DIRECTORY))?;
-        let config_path = path.join(CONFIG_NAME);
-        if !config_path.exists() {
-            fs::write(config_path, "[]")?;
+        // create_new保证并发初始化时不会覆盖其他进程已写入的配置
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.join(CONFIG_NAME))
+        {
+            Ok(mut f) => f.write_all(
+                config::encode(&Settings::default(), &Index::new(), None, &Remotes::new())?
+                    .as_bytes(),
+            )?,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
         }
-        let s = Self {
+        let mut s = Self {
             path,
             data: HashSet::new(),
+            settings: Settings::default(),
+            index: Index::new(),
+            sealed: None,
+            remotes: Remotes::new(),
+            manifests: HashMap::new(),
         };
+        s.upgrade()?;
+        let _lock = s.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        s.read_index()?;
         Ok(s)
     }
 
+    /// 基线版本的配置文件是所有条目的数组,备份后把条目拆分为清单,转换为当前格式
+    fn upgrade(&mut self) -> anyhow::Result<()> {
+        if !config::is_outdated(&read_to_string(self.config_path())?)? {
+            return Ok(());
+        }
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        let content = read_to_string(self.config_path())?;
+        // 等待锁期间可能已被其他进程转换
+        if !config::is_outdated(&content)? {
+            return Ok(());
+        }
+        self.data = config::parse_baseline(&content)?;
+        let backup = self.path.join(CONFIG_BACKUP_NAME);
+        fs::write(&backup, &content)?;
+        self.save()?;
+        info!(
+            "upgrade config to format {}, backup saved to {:?}",
+            FORMAT_VERSION, backup
+        );
+        Ok(())
+    }
+
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> anyhow::Result<Self> {
         let p = env::var(HBX_HOME_ENV);
         let hbx_home_path: Option<PathBuf> = match p {
@@ -61,6 +139,7 @@ impl Store {
             }
             Some(n) => n,
         };
+        let _gc = self.gc_lock()?;
         self.recover(root, &dst.join(&root.name))?;
         Ok(())
     }
@@ -69,17 +148,38 @@ impl Store {
     fn recover(&self, node: &Node, dst: &Path) -> anyhow::Result<()> {
         match &node.meta {
             FILE(value) => {
-                let src = self.store_dir().join(Path::new(&value));
-                info!("l {:?} -> {:?}", &src, &dst);
-                hard_link(src, dst)?;
+                let src = self
+                    .locate(value)
+                    .ok_or(anyhow!("object {} not found in the store", value))?;
+                if !src.to_string_lossy().ends_with(ZSTD_SUFFIX) {
+                    info!("l {:?} -> {:?}", &src, &dst);
+                    hard_link(src, dst)?;
+                } else {
+                    // 压缩的文件解压到目标位置,保留存储中文件的权限
+                    info!("x {:?} -> {:?}", &src, &dst);
+                    let file = File::open(&src)?;
+                    let permissions = file.metadata()?.permissions();
+                    let (_, mut reader) = compress::decompress(file)?;
+                    io::copy(&mut reader, &mut File::create(dst)?)?;
+                    fs::set_permissions(dst, permissions)?;
+                }
+            }
+            CHUNKED { mode, chunks, .. } => {
+                info!("c {:?}", dst);
+                let mut file = File::create(dst)?;
+                for chunk in chunks {
+                    let (_, mut reader) = self.read_object(chunk)?;
+                    io::copy(&mut reader, &mut file)?;
+                }
+                fs::set_permissions(dst, fs::Permissions::from_mode(*mode))?;
             }
             SYMLINK(path) => {
                 std::os::unix::fs::symlink(path, dst)?;
             }
             DIRECTORY(vec) => {
                 info!("d {:?}", dst);
-                fs::create_dir(&dst)?;
-                for x in vec.borrow().iter() {
+                fs::create_dir(dst)?;
+                for x in vec {
                     self.recover(x, &dst.join(Path::new(&x.name)))?;
                 }
             }
@@ -95,41 +195,436 @@ impl Store {
         self.path.join(Path::new(STORE_DIRECTORY))
     }
 
-    /// 加载数据
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 由load读取的条目
+    pub fn data(&self) -> &HashSet<Node> {
+        &self.data
+    }
+
+    /// 条目名称和条目清单的摘要
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// 未压缩的文件的写入位置
+    pub fn object_path(&self, digest: &str) -> PathBuf {
+        self.store_dir().join(self.settings.layout.path(digest))
+    }
+
+    /// 压缩的文件的写入位置,同一摘要的文件只会以其中一种形式写入
+    pub fn compressed_path(&self, digest: &str) -> PathBuf {
+        self.store_dir().join(
+            self.settings
+                .layout
+                .path(&format!("{}{}", digest, ZSTD_SUFFIX)),
+        )
+    }
+
+    /// 查找存储中的文件,先查找当前布局,迁移期间创建的Store可能按旧布局写入,再查找其他布局
+    pub fn locate(&self, digest: &str) -> Option<PathBuf> {
+        let compressed = format!("{}{}", digest, ZSTD_SUFFIX);
+        let others = Layout::value_variants()
+            .iter()
+            .filter(|l| **l != self.settings.layout);
+        std::iter::once(&self.settings.layout)
+            .chain(others)
+            .flat_map(|l| [l.path(digest), l.path(&compressed)])
+            .map(|p| self.store_dir().join(p))
+            .find(|p| p.exists())
+    }
+
+    pub fn has_object(&self, digest: &str) -> bool {
+        self.locate(digest).is_some()
+    }
+
+    /// 打开存储中的文件,返回原始大小和解压后的读取流
+    pub fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + Send>)> {
+        let path = self.locate(digest).ok_or(io::Error::new(
+            ErrorKind::NotFound,
+            format!("object {} not found in the store", digest),
+        ))?;
+        let file = File::open(&path)?;
+        if path.to_string_lossy().ends_with(ZSTD_SUFFIX) {
+            let (size, reader) = compress::decompress(file)?;
+            Ok((size, Box::new(reader)))
+        } else {
+            Ok((file.metadata()?.len(), Box::new(file)))
+        }
+    }
+
+    /// 写入size字节到存储,先写入临时文件,完整后再重命名。存储设置了压缩时压缩后写入
+    pub fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()> {
+        let mut tmp = NamedTempFile::new_in(self.store_dir())?;
+        let dst = match self.settings.compression {
+            Compression::Zstd => {
+                compress::compress(data, size, &mut tmp)?;
+                self.compressed_path(digest)
+            }
+            Compression::None => {
+                let n = io::copy(&mut data.take(size), &mut tmp)?;
+                if n != size {
+                    bail!("write {} incomplete, {} of {} bytes", digest, n, size);
+                }
+                self.object_path(digest)
+            }
+        };
+        info!("write {:?}", dst);
+        Self::persist(tmp, &dst)?;
+        Ok(())
+    }
+
+    /// 临时文件重命名为存储中的文件,fanout布局的子目录通常已由迁移创建
+    pub fn persist(tmp: NamedTempFile, dst: &Path) -> anyhow::Result<()> {
+        create_dir_all(dst.parent().expect("object in store"))?;
+        tmp.persist(dst)?;
+        Ok(())
+    }
+
+    /// gc读锁,持有期间delete不会清理存储中的文件
+    pub fn gc_lock(&self) -> anyhow::Result<FileLock> {
+        self.lock(GC_LOCK_NAME, LockMode::Shared)
+    }
+
+    /// 获取HBX_HOME下的锁文件
+    fn lock(&self, name: &str, mode: LockMode) -> anyhow::Result<FileLock> {
+        FileLock::acquire(&self.path.join(name), mode, Self::lock_timeout())
+    }
+
+    fn lock_timeout() -> Duration {
+        let timeout = env::var(HBX_LOCK_TIMEOUT_ENV)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT);
+        Duration::from_secs(timeout)
+    }
+
+    /// 加载所有条目
     pub fn load(&mut self) -> anyhow::Result<()> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        self.reload()
+    }
+
+    /// 从磁盘重新读取配置,只读取之前没有读取过的条目清单,调用方需持有配置锁
+    fn reload(&mut self) -> anyhow::Result<()> {
+        self.read_index()?;
+        #[cfg(feature = "sqlite")]
+        self.read_database();
+        let mut data = HashSet::with_capacity(self.index.len());
+        for digest in self.index.values() {
+            if !self.manifests.contains_key(digest) {
+                let node = self.read_manifest(digest)?;
+                self.manifests.insert(digest.clone(), node);
+            }
+            data.insert(self.manifests[digest].clone());
+        }
+        self.data = data;
+        self.forget_manifests();
+        Ok(())
+    }
+
+    /// 有SQLite索引时从索引中读取没有读取过的条目清单,索引出错时逐个读取条目清单
+    #[cfg(feature = "sqlite")]
+    fn read_database(&mut self) {
+        if self.index.values().all(|d| self.manifests.contains_key(d)) {
+            return;
+        }
+        match self.open_database() {
+            Ok(Some(db)) => match db.manifests() {
+                Ok(manifests) => self.manifests.extend(manifests),
+                Err(e) => info!("read index database failed: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => info!("update index database failed: {}", e),
+        }
+    }
+
+    /// 只保留索引中仍然引用的条目清单
+    fn forget_manifests(&mut self) {
+        let referenced: HashSet<&String> = self.index.values().collect();
+        self.manifests
+            .retain(|digest, _| referenced.contains(digest));
+    }
+
+    /// 只读取配置文件中的设置和索引,调用方需持有配置锁
+    fn read_index(&mut self) -> anyhow::Result<()> {
         let config_path = self.config_path();
         if config_path.exists() {
-            let content = read_to_string(&config_path)?;
-            let tmp: HashSet<Node> = from_str(&content)?;
-            self.data.extend(tmp);
+            (self.settings, self.index, self.sealed, self.remotes) =
+                config::parse(&read_to_string(&config_path)?)?;
         } else {
-            fs::write(config_path, "[]")?;
+            self.write_config()?;
         }
         Ok(())
     }
 
-    fn save(&self) -> anyhow::Result<()> {
-        let s = to_string(&self.data)?;
+    /// 只读取指定名称的条目,不存在的名称忽略
+    pub fn entries(&mut self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        self.read_index()?;
+        names
+            .iter()
+            .filter_map(|name| self.index.get(name))
+            .map(|digest| self.read_manifest(digest))
+            .collect()
+    }
+
+    fn read_manifest(&self, digest: &str) -> anyhow::Result<Node> {
+        let (_, mut reader) = self
+            .read_object(digest)
+            .map_err(|e| anyhow!("read entry manifest {} failed: {}", digest, e))?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        Ok(from_str(&content)?)
+    }
+
+    /// 持有配置写锁,重新加载配置后再修改并保存,保留其他进程在此期间的改动
+    pub fn update<F: FnOnce(&mut HashSet<Node>)>(&mut self, f: F) -> anyhow::Result<()> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        self.reload()?;
+        f(&mut self.data);
+        self.save()
+    }
+
+    /// 替换所有条目。指定if_match时只在当前配置的摘要与之相同时替换,返回是否替换
+    pub fn import(&mut self, data: HashSet<Node>, if_match: Option<&str>) -> anyhow::Result<bool> {
+        let mut result = Ok(true);
+        self.update(|current| {
+            result = match if_match.map(|expected| Ok(manifest::etag(current)? == expected)) {
+                Some(Ok(false)) => Ok(false),
+                Some(Err(e)) => Err(e),
+                None | Some(Ok(true)) => {
+                    *current = data;
+                    Ok(true)
+                }
+            };
+        })?;
+        result
+    }
+
+    /// 重新读取条目索引,供远程条件替换索引时比较
+    pub fn remote_index(&mut self) -> anyhow::Result<RemoteIndex> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        self.read_index()?;
+        Ok(RemoteIndex {
+            index: self.index.clone(),
+            sealed: self.sealed.clone(),
+        })
+    }
+
+    /// 替换条目索引和加密远程的索引,它们引用的条目清单和文件需已写入存储。
+    /// 指定if_match时只在当前索引的摘要与之相同时替换,返回是否替换
+    pub fn swap_index(
+        &mut self,
+        index: &RemoteIndex,
+        if_match: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        self.read_index()?;
+        let current = RemoteIndex {
+            index: self.index.clone(),
+            sealed: self.sealed.clone(),
+        };
+        if let Some(expected) = if_match {
+            if manifest::index_etag(&current)? != expected {
+                return Ok(false);
+            }
+        }
+        if let Some(digest) = index.index.values().find(|d| !self.has_object(d)) {
+            bail!("entry manifest {} not found", digest);
+        }
+        if let Some(sealed) = &index.sealed {
+            let missing = sealed
+                .refs
+                .iter()
+                .chain([&sealed.index])
+                .find(|d| !self.has_object(d));
+            if let Some(name) = missing {
+                bail!("encrypted object {} not found", name);
+            }
+        }
+        self.index = index.index.clone();
+        self.sealed = index.sealed.clone();
+        self.forget_manifests();
+        self.write_config()?;
+        #[cfg(feature = "sqlite")]
+        if let Err(e) = self.open_database() {
+            info!("update index database failed: {}", e);
+        }
+        Ok(true)
+    }
+
+    /// 加载配置时读取的设置
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// 持有配置写锁,重新加载配置后修改设置并保存
+    pub fn update_settings<F: FnOnce(&mut Settings)>(&mut self, f: F) -> anyhow::Result<()> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        self.reload()?;
+        f(&mut self.settings);
+        self.save()
+    }
+
+    /// 重新读取配置文件中命名的远程存储
+    pub fn remotes(&self) -> anyhow::Result<Remotes> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        Ok(config::parse(&read_to_string(self.config_path())?)?.3)
+    }
+
+    /// 持有配置写锁修改配置文件中的远程存储,不改动设置和条目,f返回错误时不保存
+    pub fn update_remotes<F: FnOnce(&mut Remotes) -> anyhow::Result<()>>(
+        &self,
+        f: F,
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        let (settings, index, sealed, mut remotes) =
+            config::parse(&read_to_string(self.config_path())?)?;
+        f(&mut remotes)?;
+        let s = config::encode(&settings, &index, sealed.as_ref(), &remotes)?;
         AtomicFile::new(self.config_path(), AllowOverwrite).write(|f| f.write_all(s.as_bytes()))?;
+        Ok(())
+    }
+
+    /// 上次与远程同步后各条目的内容摘要
+    pub fn sync_base(&self, remote: &str) -> anyhow::Result<SyncBase> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        let mut state: SyncState = self.read_json(SYNC_STATE_NAME)?;
+        Ok(state.remove(remote).unwrap_or_default())
+    }
+
+    pub fn update_sync_base<F: FnOnce(&mut SyncBase)>(
+        &self,
+        remote: &str,
+        f: F,
+    ) -> anyhow::Result<()> {
+        self.update_json(SYNC_STATE_NAME, |state: &mut SyncState| {
+            f(state.entry(remote.to_string()).or_default());
+            Ok(())
+        })
+    }
+
+    /// 读取HBX_HOME下的json文件,不存在时返回默认值,调用方需持有配置锁
+    fn read_json<T: DeserializeOwned + Default>(&self, name: &str) -> anyhow::Result<T> {
+        let path = self.path.join(name);
+        if !path.exists() {
+            return Ok(T::default());
+        }
+        Ok(from_str(&read_to_string(path)?)?)
+    }
+
+    /// 持有配置写锁修改HBX_HOME下的json文件,f返回错误时不保存
+    fn update_json<T, F>(&self, name: &str, f: F) -> anyhow::Result<()>
+    where
+        T: DeserializeOwned + Serialize + Default,
+        F: FnOnce(&mut T) -> anyhow::Result<()>,
+    {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        let mut value = self.read_json(name)?;
+        f(&mut value)?;
+        let s = to_string_pretty(&value)?;
+        AtomicFile::new(self.path.join(name), AllowOverwrite)
+            .write(|f| f.write_all(s.as_bytes()))?;
+        Ok(())
+    }
+
+    /// 先写入修改过的条目清单,再原子地替换配置文件中的索引,调用方需持有配置写锁。
+    /// 与加载时内容相同的条目沿用索引中的摘要,不重新序列化
+    fn save(&mut self) -> anyhow::Result<()> {
+        let mut index = Index::new();
+        for node in &self.data {
+            let unchanged = self
+                .index
+                .get(&node.name)
+                .filter(|d| self.manifests.get(*d).is_some_and(|n| n.same(node)));
+            let digest = match unchanged {
+                Some(digest) => digest.clone(),
+                None => {
+                    let (digest, content) = config::manifest(node)?;
+                    if !self.has_object(&digest) {
+                        self.write_object(&digest, content.len() as u64, &mut content.as_bytes())?;
+                    }
+                    self.manifests.insert(digest.clone(), node.clone());
+                    digest
+                }
+            };
+            index.insert(node.name.clone(), digest);
+        }
+        self.index = index;
+        self.forget_manifests();
+        self.write_config()?;
         info!("save path is {}", self.config_path().display());
+        // 索引更新失败时配置已保存,下次打开索引时会重新比较
+        #[cfg(feature = "sqlite")]
+        if let Err(e) = self.open_database() {
+            info!("update index database failed: {}", e);
+        }
         Ok(())
     }
 
+    /// 原子地写入设置、索引和远程存储,调用方需持有配置写锁
+    fn write_config(&self) -> anyhow::Result<()> {
+        let s = config::encode(
+            &self.settings,
+            &self.index,
+            self.sealed.as_ref(),
+            &self.remotes,
+        )?;
+        AtomicFile::new(self.config_path(), AllowOverwrite).write(|f| f.write_all(s.as_bytes()))?;
+        Ok(())
+    }
+
+    /// 打开SQLite索引并更新与配置不同的条目,没有创建索引时返回None。调用方需持有配置锁并已读取索引
+    #[cfg(feature = "sqlite")]
+    fn open_database(&self) -> anyhow::Result<Option<Database>> {
+        let Some(mut db) = Database::open(&self.path, Self::lock_timeout())? else {
+            return Ok(None);
+        };
+        db.sync(&self.index, &|digest| self.read_manifest(digest))?;
+        Ok(Some(db))
+    }
+
+    /// 根据配置重新创建SQLite索引,之后修改配置时同时更新索引。返回条目数
+    #[cfg(feature = "sqlite")]
+    pub fn create_database(&mut self) -> anyhow::Result<usize> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Exclusive)?;
+        self.read_index()?;
+        let mut db = Database::create(&self.path, Self::lock_timeout())?;
+        db.sync(&self.index, &|digest| self.read_manifest(digest))?;
+        Ok(self.index.len())
+    }
+
+    /// 打开与配置一致的SQLite索引
+    #[cfg(feature = "sqlite")]
+    pub fn database(&mut self) -> anyhow::Result<Database> {
+        let _lock = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        self.read_index()?;
+        self.open_database()?.ok_or(anyhow!(
+            "no index in {:?}, run hbx index import first",
+            self.path
+        ))
+    }
+
     pub fn add(&mut self, path: &Path) -> anyhow::Result<()> {
-        if path.exists() {
-            if !self.data.contains(&Node::try_from(path)?) {
-                let root = self.build(path)?;
-                self.links(&root, path)?;
-                self.data.insert(root);
-                self.save()?;
-            }
+        if path.exists() && !self.data.contains(&Node::try_from(path)?) {
+            let settings = self.settings.clone();
+            let root = self.build(path, settings.chunk_threshold)?;
+            // 持有gc读锁直到配置保存,避免并发的delete清理掉刚链接的文件
+            let _gc = self.gc_lock()?;
+            self.links(&root, path, settings.compression)?;
+            self.update(|data| {
+                data.insert(root);
+            })?;
         }
         Ok(())
     }
 
-    fn build(&self, path: &Path) -> anyhow::Result<Node> {
+    fn build(&self, path: &Path, threshold: Option<u64>) -> anyhow::Result<Node> {
         info!("build {:?}", path);
-        let root = Node::new(path)?;
+        let mut root = Self::node(path, threshold)?;
         for entry in walkdir::WalkDir::new(path)
             .follow_links(false)
             .sort_by_file_name()
@@ -139,94 +634,225 @@ impl Store {
             .filter(|f| f.path() != path)
         {
             let node = if entry.path().is_dir() {
-                self.build(entry.path())?
+                self.build(entry.path(), threshold)?
             } else {
-                Node::new(entry.path())?
+                Self::node(entry.path(), threshold)?
             };
 
-            match &root.meta {
-                DIRECTORY(vec) => {
-                    vec.borrow_mut().push(node);
-                }
-                _ => {}
+            if let DIRECTORY(vec) = &mut root.meta {
+                vec.push(node);
             }
         }
         Ok(root)
     }
 
-    fn links(&self, root: &Node, src: &Path) -> anyhow::Result<()> {
+    /// 不小于threshold的文件按内容切分
+    fn node(path: &Path, threshold: Option<u64>) -> anyhow::Result<Node> {
+        match threshold {
+            Some(threshold)
+                if !path.is_symlink() && path.is_file() && path.metadata()?.len() >= threshold =>
+            {
+                let name = path
+                    .file_name()
+                    .ok_or(anyhow!("invalid path"))?
+                    .to_string_lossy()
+                    .to_string();
+                Ok(Node {
+                    name,
+                    meta: chunk::split(path)?,
+                })
+            }
+            _ => Node::new(path),
+        }
+    }
+
+    /// 未压缩时把文件硬链接到存储中,压缩时写入压缩后的副本
+    fn links(&self, root: &Node, src: &Path, compression: Compression) -> anyhow::Result<()> {
         match &root.meta {
-            FILE(value) => {
-                let dst = self.store_dir().join(Path::new(value));
-                info!("l {:?} -> {:?}", &src, &dst);
-                hard_link(src, dst)?;
+            FILE(value) if self.has_object(value) => {}
+            FILE(value) => match compression {
+                Compression::None => {
+                    let dst = self.object_path(value);
+                    info!("l {:?} -> {:?}", &src, &dst);
+                    create_dir_all(dst.parent().expect("object in store"))?;
+                    hard_link(src, dst)?;
+                }
+                Compression::Zstd => {
+                    let dst = self.compressed_path(value);
+                    info!("z {:?} -> {:?}", &src, &dst);
+                    let mut file = File::open(src)?;
+                    let meta = file.metadata()?;
+                    let mut tmp = NamedTempFile::new_in(self.store_dir())?;
+                    compress::compress(&mut file, meta.len(), &mut tmp)?;
+                    fs::set_permissions(tmp.path(), meta.permissions())?;
+                    Self::persist(tmp, &dst)?;
+                }
+            },
+            CHUNKED { chunks, .. } if chunks.iter().all(|c| self.has_object(c)) => {}
+            // 重新切分文件,块的顺序与添加时相同,只写入存储中没有的块。
+            // 文件在切分后被修改时块的摘要不一致,拒绝写入
+            CHUNKED { chunks, .. } => {
+                let changed = || anyhow!("{:?} changed while adding, add it again", src);
+                let mut split = Chunker::new(File::open(src)?);
+                for digest in chunks {
+                    let data = split.next().transpose()?.ok_or_else(changed)?;
+                    if format!("{:x}", md5::Md5::digest(&data)) != *digest {
+                        return Err(changed());
+                    }
+                    if !self.has_object(digest) {
+                        self.write_object(digest, data.len() as u64, &mut data.as_slice())?;
+                    }
+                }
+                if split.next().is_some() {
+                    return Err(changed());
+                }
             }
             SYMLINK(_) => {}
             DIRECTORY(vec) => {
-                for node in vec.borrow().iter() {
-                    self.links(node, &src.join(Path::new(&node.name)))?;
+                for node in vec {
+                    self.links(node, &src.join(Path::new(&node.name)), compression)?;
                 }
             }
         }
         Ok(())
     }
 
+    /// 条目名称只需要读取索引
     pub fn list(&self) -> Vec<&str> {
-        let mut ans = Vec::new();
-        for x in &self.data {
-            ans.push(x.name.as_str());
-        }
-        ans
+        self.index.keys().map(|name| name.as_str()).collect()
     }
 
     pub fn delete(&mut self, name: &str) -> anyhow::Result<()> {
-        self.data.remove(&Node::sample(name));
-        self.save()?;
+        self.update(|data| {
+            data.remove(&Node::sample(name));
+        })?;
         self.clear()?;
         Ok(())
     }
 
-    fn clear(&self) -> anyhow::Result<()> {
-        let names = walkdir::WalkDir::new(self.store_dir())
-            .follow_links(false)
-            .into_iter()
-            .filter_map(|f| f.ok())
-            .filter(|p| p.path() != self.store_dir())
-            .map(|p| p.file_name().to_string_lossy().to_string())
-            .collect::<HashSet<String>>();
-        let mut tmp = HashSet::new();
-
-        fn dfs(node: &Node, tmp: &mut HashSet<String>) {
-            match &node.meta {
-                FILE(x) => {
-                    tmp.insert(x.to_owned());
-                }
-                DIRECTORY(nodes) => {
-                    for x in nodes.borrow().iter() {
-                        dfs(x, tmp);
-                    }
-                }
-                _ => {}
-            };
+    fn clear(&mut self) -> anyhow::Result<()> {
+        self.gc(false, Duration::ZERO)?;
+        Ok(())
+    }
+
+    /// 删除存储中没有被配置引用的文件,dry_run时只列出。
+    /// 最近grace时间内写入的文件可能属于尚未更新配置的推送,不会删除。返回文件摘要和大小
+    pub fn gc(&mut self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>> {
+        // gc写锁保证没有正在写入的文件,配置读锁保证清理期间索引不变,基于最新的配置计算引用
+        let _gc = self.lock(GC_LOCK_NAME, LockMode::Exclusive)?;
+        let _config = self.lock(CONFIG_LOCK_NAME, LockMode::Shared)?;
+        let referenced = self.referenced()?;
+        let now = SystemTime::now();
+        let mut ans = Vec::new();
+        for entry in self.objects() {
+            let name = layout::name(entry.path().strip_prefix(self.store_dir())?);
+            let digest = name.strip_suffix(ZSTD_SUFFIX).unwrap_or(&name).to_string();
+            if referenced.contains(&digest) {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|t| now.duration_since(t).ok())
+                .unwrap_or_default();
+            if age < grace {
+                info!("keep recent unreferenced {:?}", entry.path());
+                continue;
+            }
+            if !dry_run {
+                info!("delete {:?}", entry.path());
+                fs::remove_file(entry.path())?;
+            }
+            ans.push((digest, meta.len()));
+        }
+        ans.sort();
+        #[cfg(feature = "sqlite")]
+        if let (false, Some(mut db)) = (dry_run, self.open_database()?) {
+            let removed: Vec<String> = ans.iter().map(|(digest, _)| digest.clone()).collect();
+            db.forget(&removed)?;
         }
+        Ok(ans)
+    }
 
-        for node in &self.data {
-            dfs(&node, &mut tmp);
+    /// 配置引用的所有文件,包括条目清单和加密远程的密文。
+    /// 调用方需持有配置锁
+    fn referenced(&mut self) -> anyhow::Result<HashSet<String>> {
+        let mut referenced = self.entry_objects()?;
+        if let Some(sealed) = &self.sealed {
+            referenced.extend(sealed.objects(&|name| {
+                let (_, mut reader) = self.read_object(name)?;
+                let mut content = String::new();
+                reader.read_to_string(&mut content)?;
+                Ok(content)
+            })?);
         }
+        Ok(referenced)
+    }
 
-        let res: HashSet<_> = names
-            .difference(&tmp)
-            .map(|name| self.store_dir().join(PathBuf::from(name)))
-            .collect();
+    /// 条目引用的所有文件,包括条目清单。有SQLite索引时读取引用计数,不需要遍历所有条目
+    fn entry_objects(&mut self) -> anyhow::Result<HashSet<String>> {
+        #[cfg(feature = "sqlite")]
+        {
+            self.read_index()?;
+            if let Some(db) = self.open_database()? {
+                return db.referenced();
+            }
+        }
+        self.reload()?;
+        let mut referenced = Self::get_files(&mut self.data.iter());
+        referenced.extend(self.index.values().cloned());
+        Ok(referenced)
+    }
+
+    /// 存储目录中的所有文件,包括fanout布局子目录中的文件
+    fn objects(&self) -> Vec<walkdir::DirEntry> {
+        walkdir::WalkDir::new(self.store_dir())
+            .follow_links(false)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|f| f.ok())
+            .filter(|f| f.file_type().is_file())
+            .collect()
+    }
 
-        for path in res {
-            info!("delete {:?}", path);
-            fs::remove_file(path)?;
+    /// 把存储中的文件移动到新布局的位置并保存设置,返回移动的文件数。
+    /// 持有gc写锁,期间没有其他进程读写文件;中断后再次执行可以继续迁移
+    pub fn migrate(&mut self, layout: Layout) -> anyhow::Result<usize> {
+        let _gc = self.lock(GC_LOCK_NAME, LockMode::Exclusive)?;
+        for dir in layout.directories() {
+            create_dir_all(self.store_dir().join(dir))?;
         }
-        Ok(())
+        let mut moved = 0;
+        for entry in self.objects() {
+            let name = layout::name(entry.path().strip_prefix(self.store_dir())?);
+            // 写入中断留下的临时文件由gc清理
+            if name.starts_with('.') {
+                continue;
+            }
+            let dst = self.store_dir().join(layout.path(&name));
+            if dst != entry.path() {
+                info!("mv {:?} -> {:?}", entry.path(), dst);
+                create_dir_all(dst.parent().expect("object in store"))?;
+                fs::rename(entry.path(), &dst)?;
+                moved += 1;
+            }
+        }
+        self.update_settings(|settings| settings.layout = layout)?;
+        if layout == Layout::Flat {
+            for entry in fs::read_dir(self.store_dir())? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    // 不为空的目录保留
+                    let _ = fs::remove_dir(path);
+                }
+            }
+        }
+        Ok(moved)
     }
 
-    pub fn info(&self) -> anyhow::Result<String> {
+    /// 存储的路径、版本和设置。sizes为true时统计所有文件的大小,ssh远程每次连接都会运行 `hbx info`,默认不统计
+    pub fn info(&self, sizes: bool) -> anyhow::Result<String> {
         let mut map = HashMap::<String, String>::new();
         map.insert(
             "config".into(),
@@ -236,129 +862,374 @@ impl Store {
             "storage".into(),
             self.store_dir().to_string_lossy().to_string(),
         );
+        map.insert("version".into(), env!("CARGO_PKG_VERSION").into());
+        map.insert("format_version".into(), FORMAT_VERSION.to_string());
+        let settings = &self.settings;
+        if let Some(c) = settings.compression.to_possible_value() {
+            map.insert("compression".into(), c.get_name().to_string());
+        }
+        if let Some(l) = settings.layout.to_possible_value() {
+            map.insert("layout".into(), l.get_name().to_string());
+        }
+        if sizes {
+            let (logical, physical) = self.sizes()?;
+            map.insert("logical_size".into(), logical.to_string());
+            map.insert("physical_size".into(), physical.to_string());
+        }
         Ok(to_string(&map)?)
     }
 
+    /// 存储中文件的原始大小之和与占用的空间
+    fn sizes(&self) -> anyhow::Result<(u64, u64)> {
+        let (mut logical, mut physical) = (0, 0);
+        for entry in self.objects() {
+            let path = entry.path();
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            physical += size;
+            logical += if path.to_string_lossy().ends_with(ZSTD_SUFFIX) {
+                let mut header = Vec::new();
+                file.take(64).read_to_end(&mut header)?;
+                compress::content_size(&header)?
+            } else {
+                size
+            };
+        }
+        Ok((logical, physical))
+    }
+
+    /// 下载远程条目,替换本地内容不同的同名条目。key是远程的地址,用于读取和更新同步记录
     pub fn pull(
         &mut self,
-        address: String,
+        remotes: &[Box<dyn Transport>],
+        key: &str,
         names: Vec<String>,
-        port: Option<String>,
         all: bool,
+        overwrite: Overwrite,
+        limit_rate: Option<u64>,
     ) -> anyhow::Result<()> {
-        let agent = Self::login_server(address, port)?;
-
-        if !Self::remote_has_hbx(&agent)? {
-            bail!("server not install hbx");
-        }
-
-        // 读取服务器端配置信息
-        let map = Self::remote_hbx_info(&agent)?;
-        let remote_config = map.get("config").ok_or(anyhow!("config info error"))?;
-        let remote_storage = map.get("storage").ok_or(anyhow!("storage info error"))?;
-
-        // 下载配置文件到本地
-        let tmp = tempfile::tempdir()?;
-        let dst_file = tmp.path().join(CONFIG_NAME);
-        agent.download(&dst_file, &PathBuf::from(remote_config))?;
-
-        // 加载远程配置文件
-        let remote_data: HashSet<Node> = from_str(&read_to_string(&dst_file)?)?;
+        // 读取远程配置,只同步部分条目时只读取这些条目
+        let remote_data = if all {
+            remotes[0].config()?
+        } else {
+            remotes[0].entries(&names)?
+        };
 
-        // 比对差异文件
+        // 比对差异
         let mut target = HashSet::new();
         Self::filter(names, all, &remote_data, &mut target)?;
-        let diff = Self::get_diff(
-            &mut target,
-            &mut self.data.iter().collect::<HashSet<&Node>>(),
-        )?;
+        let changes = sync::copy_plan(&target, &self.data, &self.sync_base(key)?, overwrite)?;
+        let copied = Self::copied(&changes);
 
-        // 下载差异文件
-        for item in diff {
-            let remote = PathBuf::from(remote_storage).join(PathBuf::from(&item));
-            let local = self.store_dir().join(PathBuf::from(&item));
-            if !&local.exists() {
-                agent.download(&local, &remote)?;
+        let meter = Meter::new(limit_rate);
+        {
+            // 下载差异文件,全部下载成功后才更新本地配置
+            let _gc = self.gc_lock()?;
+            let diff = Self::get_files(&mut copied.iter().copied())
+                .into_iter()
+                .filter(|item| !self.has_object(item))
+                .collect();
+            parallel(remotes, diff, |remote, item| {
+                let (size, mut reader) = remote.read_object(item)?;
+                self.write_object(item, size, &mut meter.reader(&mut reader))
+            })?;
+
+            let mut changed = Vec::new();
+            self.update(|data| Self::apply(data, &changes, &mut changed))?;
+            if !changed.is_empty() {
+                bail!(
+                    "local entries changed during pull: {}, run pull again",
+                    changed.join(", ")
+                );
             }
         }
-
-        // 合并远程和本地配置
-        self.data.extend(target.into_iter().map(|f| f.to_owned()));
-
-        self.save()?;
+        // 替换后旧版本的文件不再被引用
+        if changes.iter().any(|c| c.action == Action::Updated) {
+            self.clear()?;
+        }
+        self.record(key, &changes)?;
+        println!("{}", meter);
         Ok(())
     }
 
-    fn remote_has_hbx(agent: &Agent) -> anyhow::Result<bool> {
-        // 判断服务上是否安装hbx命令
-        let res = agent.execute("[ -f /usr/local/bin/hbx ] && echo 0 || echo 1")?;
-        let res = res.trim();
-        Ok(res.eq("0"))
-    }
-
-    fn get_files(data: &mut dyn Iterator<Item = &Node>) -> HashSet<String> {
+    /// 条目引用的所有文件摘要
+    pub fn get_files(data: &mut dyn Iterator<Item = &Node>) -> HashSet<String> {
         let mut ans = HashSet::new();
         for item in data {
             if let FILE(s) = &item.meta {
                 ans.insert(s.to_string());
             }
+            if let CHUNKED { chunks, .. } = &item.meta {
+                ans.extend(chunks.iter().cloned());
+            }
             if let DIRECTORY(children) = &item.meta {
-                ans.extend(Store::get_files(&mut children.borrow().iter()));
+                ans.extend(Store::get_files(&mut children.iter()));
             }
         }
         ans
     }
 
+    /// 上传本地条目,替换远程内容不同的同名条目。key是远程的地址,用于读取和更新同步记录
     pub fn push(
         &self,
-        address: String,
+        remotes: &[Box<dyn Transport>],
+        key: &str,
         names: Vec<String>,
-        port: Option<String>,
-        install: bool,
         all: bool,
+        overwrite: Overwrite,
+        limit_rate: Option<u64>,
     ) -> anyhow::Result<()> {
-        let agent = Self::login_server(address, port)?;
+        // 读取远程配置,只同步部分条目时只读取同名的条目,
+        // 上传时只跳过这些条目引用的文件
+        let remote = &remotes[0];
+        let remote_data = if all {
+            remote.config()?
+        } else {
+            remote.entries(&names)?
+        };
 
-        if !Self::remote_has_hbx(&agent)? {
-            if install {
-                info!("server install hbx ...");
-                agent.upload(&env::current_exe()?, &PathBuf::from("/usr/local/bin/hbx"))?;
-            } else {
-                bail!("remote server not install hbx!!!");
-            }
-        }
+        // 计算差异
+        let mut target = HashSet::new();
+        Self::filter(names, all, &self.data, &mut target)?;
+        let changes = sync::copy_plan(&target, &remote_data, &self.sync_base(key)?, overwrite)?;
+        let copied = Self::copied(&changes);
+        let diff = Self::get_diff(&copied.into_iter().collect(), &remote_data.iter().collect())?;
 
-        // 读取服务器端配置信息
-        let map = Self::remote_hbx_info(&agent)?;
-        // 下载配置文件到本地
-        let remote_config = map.get("config").ok_or(anyhow!("config info error"))?;
-        let remote_storage = map.get("storage").ok_or(anyhow!("storage info error"))?;
+        // 上传差异文件,全部上传成功后才更新远程配置
+        let _gc = self.gc_lock()?;
+        let meter = Meter::new(limit_rate);
+        parallel(remotes, diff.into_iter().collect(), |remote, item| {
+            let (size, mut reader) = self.read_object(item)?;
+            remote.write_object(item, size, &mut meter.reader(&mut reader))
+        })?;
 
-        let tmp = tempfile::tempdir()?;
-        let dst_file = tmp.path().join(CONFIG_NAME);
-        agent.download(&dst_file, &PathBuf::from(remote_config))?;
+        // 写入远程配置
+        let mut changed = Vec::new();
+        remote.update_entries(&Self::names(&changes), &mut |data| {
+            Self::apply(data, &changes, &mut changed)
+        })?;
+        if !changed.is_empty() {
+            bail!(
+                "remote entries changed during push: {}, run push again",
+                changed.join(", ")
+            );
+        }
+        self.record(key, &changes)?;
+        println!("{}", meter);
+        Ok(())
+    }
 
-        // 加载远程配置文件
-        let mut remote_data: HashSet<Node> = from_str(&read_to_string(&dst_file)?)?;
+    /// 把src中的条目复制到dst,缺少的文件从src读取后直接写入dst,不经过本地存储。
+    /// 两个远程之间没有同步记录,内容不同的条目直接替换,除非指定了 --no-overwrite
+    pub fn relay(
+        src: &[Box<dyn Transport>],
+        dst: &[Box<dyn Transport>],
+        names: Vec<String>,
+        all: bool,
+        overwrite: Overwrite,
+        limit_rate: Option<u64>,
+    ) -> anyhow::Result<()> {
+        // 只中转部分条目时两边都只读取同名的条目,与push相同,上传时只跳过这些条目引用的文件
+        let (src_data, dst_data) = if all {
+            (src[0].config()?, dst[0].config()?)
+        } else {
+            (src[0].entries(&names)?, dst[0].entries(&names)?)
+        };
 
-        // 计算差异
         let mut target = HashSet::new();
-        Self::filter(names, all, &self.data, &mut target)?;
-        let diff = Self::get_diff(&mut target, &mut remote_data.iter().collect())?;
+        Self::filter(names, all, &src_data, &mut target)?;
+        let changes = sync::copy_plan(&target, &dst_data, &SyncBase::new(), overwrite)?;
+        let copied = Self::copied(&changes);
+        let diff = Self::get_diff(&copied.into_iter().collect(), &dst_data.iter().collect())?;
+
+        // 每对连接一个线程,全部传输成功后才更新目标配置
+        let pairs: Vec<_> = src.iter().zip(dst).collect();
+        let meter = Meter::new(limit_rate);
+        parallel(&pairs, diff.into_iter().collect(), |(src, dst), item| {
+            let (size, mut reader) = src.read_object(item)?;
+            dst.write_object(item, size, &mut meter.reader(&mut reader))
+        })?;
 
-        // 上传差异文件
-        for item in diff {
-            let remote = PathBuf::from(remote_storage).join(PathBuf::from(&item));
-            let local = self.store_dir().join(PathBuf::from(&item));
-            agent.upload(&local, &remote)?;
+        let mut changed = Vec::new();
+        dst[0].update_entries(&Self::names(&changes), &mut |data| {
+            Self::apply(data, &changes, &mut changed)
+        })?;
+        if !changed.is_empty() {
+            bail!(
+                "destination entries changed during relay: {}, run relay again",
+                changed.join(", ")
+            );
         }
+        for c in &changes {
+            println!("{:<10} {}", c.action.to_string(), c.node.name);
+        }
+        println!("{}", meter);
+        Ok(())
+    }
+
+    /// 需要创建或替换的条目
+    fn copied(changes: &[Change]) -> Vec<&Node> {
+        changes
+            .iter()
+            .filter(|c| matches!(c.action, Action::Created | Action::Updated))
+            .map(|c| &c.node)
+            .collect()
+    }
 
-        // 合并本地配置到远程
-        remote_data.extend(target.into_iter().map(|f| f.to_owned()));
-        agent.write_remote_file(&to_string(&remote_data)?, &PathBuf::from(remote_config))?;
+    /// 计划中所有条目的名称,写入远程时只读取和修改这些条目
+    fn names(changes: &[Change]) -> Vec<String> {
+        changes.iter().map(|c| c.node.name.clone()).collect()
+    }
+
+    /// 写入创建和替换的条目。目标条目在计划后被修改时记录到changed,不做任何修改
+    fn apply(data: &mut HashSet<Node>, changes: &[Change], changed: &mut Vec<String>) {
+        *changed = changes
+            .iter()
+            .filter(|c| data.get(&c.node).map(Node::digest) != c.expected)
+            .map(|c| c.node.name.clone())
+            .collect();
+        if !changed.is_empty() {
+            return;
+        }
+        for node in Self::copied(changes) {
+            data.replace(node.clone());
+        }
+    }
+
+    /// 两边内容相同的条目记录到同步记录中,并输出每个条目的操作
+    fn record(&self, key: &str, changes: &[Change]) -> anyhow::Result<()> {
+        self.update_sync_base(key, |base| {
+            for c in changes.iter().filter(|c| c.action != Action::Skipped) {
+                base.insert(c.node.name.clone(), c.node.digest());
+            }
+        })?;
+        for c in changes {
+            println!("{:<10} {}", c.action.to_string(), c.node.name);
+        }
         Ok(())
     }
 
+    /// 双向同步: 只在一边变化的条目同步到另一边,两边都变化的条目按conflict处理,未指定时只报告。
+    /// key是远程的地址,用于保存上次同步后的条目摘要
+    pub fn sync(
+        &mut self,
+        remotes: &[Box<dyn Transport>],
+        key: &str,
+        names: Vec<String>,
+        conflict: Option<Conflict>,
+        limit_rate: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let remote = &remotes[0];
+        let remote_data = remote.config()?;
+        let base = self.sync_base(key)?;
+        let plan = sync::plan(&self.data, &remote_data, &base, &names, conflict)?;
+
+        let meter = Meter::new(limit_rate);
+        {
+            let _gc = self.gc_lock()?;
+            // 传输文件,全部成功后才修改两边的配置
+            let upload =
+                Self::get_diff(&plan.push.iter().collect(), &remote_data.iter().collect())?;
+            parallel(remotes, upload.into_iter().collect(), |remote, item| {
+                let (size, mut reader) = self.read_object(item)?;
+                remote.write_object(item, size, &mut meter.reader(&mut reader))
+            })?;
+            let download = Self::get_files(&mut plan.pull.iter())
+                .into_iter()
+                .filter(|item| !self.has_object(item))
+                .collect();
+            parallel(remotes, download, |remote, item| {
+                let (size, mut reader) = remote.read_object(item)?;
+                self.write_object(item, size, &mut meter.reader(&mut reader))
+            })?;
+
+            // 远程条目在同步期间被修改时放弃,下次同步会重新比较
+            let mut changed = Vec::new();
+            let names: Vec<String> = plan.expected.keys().cloned().collect();
+            remote.update_entries(&names, &mut |data| {
+                changed = Self::changed(data, &plan.expected);
+                if !changed.is_empty() {
+                    return;
+                }
+                for name in &plan.remove_remote {
+                    data.remove(&Node::sample(name));
+                }
+                for node in &plan.push {
+                    data.replace(node.clone());
+                }
+            })?;
+            if !changed.is_empty() {
+                bail!(
+                    "remote entries changed during sync: {}, run sync again",
+                    changed.join(", ")
+                );
+            }
+
+            // 与pull相同,本地条目在同步期间被修改时不覆盖
+            self.update(|data| {
+                changed = Self::changed(data, &plan.expected_local);
+                if !changed.is_empty() {
+                    return;
+                }
+                for name in &plan.remove_local {
+                    data.remove(&Node::sample(name));
+                }
+                for node in &plan.pull {
+                    data.replace(node.clone());
+                }
+            })?;
+            if !changed.is_empty() {
+                bail!(
+                    "local entries changed during sync: {}, run sync again",
+                    changed.join(", ")
+                );
+            }
+        }
+        if !plan.remove_local.is_empty() {
+            self.clear()?;
+        }
+        self.update_sync_base(key, |base| {
+            for (name, digest) in &plan.base {
+                match digest {
+                    Some(digest) => base.insert(name.clone(), digest.clone()),
+                    None => base.remove(name),
+                };
+            }
+        })?;
+
+        let node_names = |nodes: &[Node]| nodes.iter().map(|n| n.name.clone()).collect();
+        for (action, names) in [
+            ("pushed", node_names(&plan.push)),
+            ("pulled", node_names(&plan.pull)),
+            ("removed remotely", plan.remove_remote.clone()),
+            ("removed locally", plan.remove_local.clone()),
+            ("up-to-date", plan.unchanged.clone()),
+            ("conflict", plan.conflicts.clone()),
+        ] {
+            for name in names {
+                println!("{:<16} {}", action, name);
+            }
+        }
+        println!("{}", meter);
+        if !plan.conflicts.is_empty() {
+            bail!(
+                "{} entries changed on both sides: {}, use --conflict local|remote|both to resolve",
+                plan.conflicts.len(),
+                plan.conflicts.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// 摘要与计划时不同的条目名称
+    fn changed(data: &HashSet<Node>, expected: &BTreeMap<String, Option<String>>) -> Vec<String> {
+        expected
+            .iter()
+            .filter(|(name, digest)| data.get(&Node::sample(name)).map(Node::digest) != **digest)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     fn get_diff(src: &HashSet<&Node>, other: &HashSet<&Node>) -> anyhow::Result<HashSet<String>> {
         let ans = Self::get_files(&mut src.iter().map(|f| f.to_owned()))
             .difference(&Self::get_files(&mut other.iter().map(|f| f.to_owned())))
@@ -385,26 +1256,4 @@ impl Store {
         }
         Ok(())
     }
-
-    fn remote_hbx_info(agent: &Agent) -> anyhow::Result<HashMap<String, String>> {
-        let info = agent.execute("hbx info")?;
-        let info = info.trim();
-        info!("remote info: {}", info);
-        let map = from_str::<HashMap<String, String>>(&info)?;
-        Ok(map)
-    }
-
-    fn login_server(address: String, port: Option<String>) -> anyhow::Result<Agent> {
-        // 正则判断是别名，还是网络地址 todo
-        let address: Vec<&str> = address.split("@").collect();
-
-        let username = address[0];
-        let host = address[1].to_string() + ":" + port.unwrap_or("22".into()).as_str();
-        info!("username : {}, host: {}", username, host);
-
-        // 登陆远程服务器
-        let mut agent = Agent::new()?;
-        agent.login(username, &host)?;
-        Ok(agent)
-    }
 }
@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+
+use anyhow::bail;
+use clap::ValueEnum;
+
+use crate::core::node::Node;
+
+/// 条目名称到上次同步后内容摘要的映射
+pub type SyncBase = BTreeMap<String, String>;
+/// 每个远程地址的同步记录
+pub type SyncState = BTreeMap<String, SyncBase>;
+
+/// 两边都修改了同一条目时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Conflict {
+    /// keep the local version on both sides
+    Local,
+    /// keep the remote version on both sides
+    Remote,
+    /// keep the local version and add the remote version under an alias
+    Both,
+}
+
+/// 同步需要执行的操作
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// 写入远程的条目,替换远程的同名条目
+    pub push: Vec<Node>,
+    /// 写入本地的条目,替换本地的同名条目
+    pub pull: Vec<Node>,
+    pub remove_remote: Vec<String>,
+    pub remove_local: Vec<String>,
+    /// 未处理的冲突
+    pub conflicts: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// 修改远程前远程条目应有的摘要,不一致说明同步期间被其他人修改
+    pub expected: BTreeMap<String, Option<String>>,
+    /// 修改本地前本地条目应有的摘要,不一致说明同步期间被本地的其他hbx进程修改
+    pub expected_local: BTreeMap<String, Option<String>>,
+    /// 同步后的记录,None表示两边都已删除
+    pub base: BTreeMap<String, Option<String>>,
+}
+
+/// 保留哪一边的版本
+enum Take {
+    Local,
+    Remote,
+    Both,
+}
+
+/// 比较本地、远程和上次同步的摘要: 只有一边变化时把变化同步到另一边,两边都变化时为冲突。
+/// names为空时同步两边的所有条目
+pub fn plan(
+    local: &HashSet<Node>,
+    remote: &HashSet<Node>,
+    base: &SyncBase,
+    names: &[String],
+    conflict: Option<Conflict>,
+) -> anyhow::Result<Plan> {
+    let names: BTreeSet<String> = if names.is_empty() {
+        local.iter().chain(remote).map(|n| n.name.clone()).collect()
+    } else {
+        names.iter().cloned().collect()
+    };
+
+    let mut plan = Plan::default();
+    for name in names {
+        let key = Node::sample(&name);
+        let (l, r) = (local.get(&key), remote.get(&key));
+        if l.is_none() && r.is_none() && !base.contains_key(&name) {
+            bail!("{} not found locally or on the remote", name);
+        }
+        let (ld, rd) = (l.map(Node::digest), r.map(Node::digest));
+        let bd = base.get(&name).cloned();
+        if ld == rd {
+            if ld.is_some() {
+                plan.unchanged.push(name.clone());
+            }
+            plan.base.insert(name, ld);
+            continue;
+        }
+
+        let take = if rd == bd {
+            Take::Local
+        } else if ld == bd {
+            Take::Remote
+        } else {
+            match conflict {
+                None => {
+                    plan.conflicts.push(name);
+                    continue;
+                }
+                Some(Conflict::Local) => Take::Local,
+                Some(Conflict::Remote) => Take::Remote,
+                Some(Conflict::Both) => Take::Both,
+            }
+        };
+
+        match (take, l, r) {
+            (Take::Local, Some(l), _) | (Take::Both, Some(l), None) => {
+                plan.push.push(l.clone());
+                plan.expected.insert(name.clone(), rd);
+                plan.base.insert(name, ld);
+            }
+            (Take::Local, None, _) => {
+                plan.remove_remote.push(name.clone());
+                plan.expected.insert(name.clone(), rd);
+                plan.base.insert(name, None);
+            }
+            (Take::Remote, _, Some(r)) | (Take::Both, None, Some(r)) => {
+                plan.pull.push(r.clone());
+                plan.expected_local.insert(name.clone(), ld);
+                plan.base.insert(name, rd);
+            }
+            (Take::Remote, _, None) => {
+                plan.remove_local.push(name.clone());
+                plan.expected_local.insert(name.clone(), ld);
+                plan.base.insert(name, None);
+            }
+            (Take::Both, Some(l), Some(r)) => {
+                // 远程版本以别名保存到两边,别名包含内容摘要,不会与其他条目重名
+                let mut alias = r.clone();
+                alias.name = format!("{}~{}", r.name, &r.digest()[..8]);
+                plan.push.push(l.clone());
+                plan.expected.insert(name.clone(), rd);
+                plan.base.insert(name, ld);
+                plan.expected
+                    .insert(alias.name.clone(), remote.get(&alias).map(Node::digest));
+                plan.expected_local
+                    .insert(alias.name.clone(), local.get(&alias).map(Node::digest));
+                plan.base.insert(alias.name.clone(), Some(alias.digest()));
+                plan.push.push(alias.clone());
+                plan.pull.push(alias);
+            }
+            (Take::Both, None, None) => {}
+        }
+    }
+    Ok(plan)
+}
+
+/// push和pull覆盖目标中已有条目的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    /// 替换内容不同的条目,目标在上次同步后也被修改过时拒绝
+    #[default]
+    Safe,
+    /// 总是替换内容不同的条目
+    Force,
+    /// 不替换已有的条目
+    Never,
+}
+
+/// push和pull对单个条目的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Created,
+    Updated,
+    Unchanged,
+    /// 内容不同但指定了 --no-overwrite
+    Skipped,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Action::Created => "created",
+            Action::Updated => "updated",
+            Action::Unchanged => "unchanged",
+            Action::Skipped => "skipped",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 复制到目标的条目
+#[derive(Debug)]
+pub struct Change {
+    pub node: Node,
+    pub action: Action,
+    /// 计划时目标中同名条目的摘要,写入前再次比较,不一致说明期间被其他人修改
+    pub expected: Option<String>,
+}
+
+/// push和pull: 比较src中的条目与目标中的同名条目,决定创建、替换还是跳过
+pub fn copy_plan(
+    src: &HashSet<&Node>,
+    dst: &HashSet<Node>,
+    base: &SyncBase,
+    overwrite: Overwrite,
+) -> anyhow::Result<Vec<Change>> {
+    let mut ans = Vec::new();
+    let mut diverged = Vec::new();
+    let mut src: Vec<&Node> = src.iter().copied().collect();
+    src.sort_by(|a, b| a.name.cmp(&b.name));
+    for node in src {
+        let expected = dst.get(node).map(Node::digest);
+        let action = match &expected {
+            None => Action::Created,
+            Some(d) if *d == node.digest() => Action::Unchanged,
+            Some(_) if overwrite == Overwrite::Never => Action::Skipped,
+            Some(d)
+                if overwrite == Overwrite::Safe && base.get(&node.name).is_some_and(|b| b != d) =>
+            {
+                diverged.push(node.name.clone());
+                continue;
+            }
+            Some(_) => Action::Updated,
+        };
+        ans.push(Change {
+            node: node.clone(),
+            action,
+            expected,
+        });
+    }
+    if !diverged.is_empty() {
+        bail!(
+            "{} changed on the destination since the last sync, use sync to merge or --force to replace",
+            diverged.join(", ")
+        );
+    }
+    Ok(ans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::node::Meta;
+
+    fn file(name: &str, content: &str) -> Node {
+        Node {
+            name: name.into(),
+            meta: Meta::FILE(content.into()),
+        }
+    }
+
+    fn set(nodes: &[Node]) -> HashSet<Node> {
+        nodes.iter().cloned().collect()
+    }
+
+    fn base(nodes: &[Node]) -> SyncBase {
+        nodes.iter().map(|n| (n.name.clone(), n.digest())).collect()
+    }
+
+    fn names(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    /// Node只按名称比较,这里比较内容摘要
+    fn digests(nodes: &[Node]) -> Vec<String> {
+        nodes.iter().map(Node::digest).collect()
+    }
+
+    #[test]
+    fn one_side_changes() {
+        let (a, b, c) = (file("a", "1"), file("b", "1"), file("c", "1"));
+        let (a2, b2) = (file("a", "2"), file("b", "2"));
+        // a只在本地修改,b只在远程修改,c没有变化,d和e是新条目
+        let local = set(&[a2.clone(), b.clone(), c.clone(), file("d", "1")]);
+        let remote = set(&[a.clone(), b2.clone(), c.clone(), file("e", "1")]);
+        let plan = plan(
+            &local,
+            &remote,
+            &base(&[a.clone(), b.clone(), c]),
+            &[],
+            None,
+        )
+        .unwrap();
+        assert_eq!(names(&plan.push), ["a", "d"]);
+        assert_eq!(names(&plan.pull), ["b", "e"]);
+        assert_eq!(plan.unchanged, ["c"]);
+        assert!(plan.conflicts.is_empty());
+        assert!(plan.remove_local.is_empty() && plan.remove_remote.is_empty());
+        // 写入远程前检查远程仍是计划时的版本
+        assert_eq!(plan.expected["a"], Some(a.digest()));
+        assert_eq!(plan.expected["d"], None);
+        // 写入本地前同样检查本地仍是计划时的版本
+        assert_eq!(plan.expected_local["b"], Some(b.digest()));
+        assert_eq!(plan.expected_local["e"], None);
+        assert!(!plan.expected_local.contains_key("a"));
+        assert_eq!(plan.base["a"], Some(a2.digest()));
+        assert_eq!(plan.base["b"], Some(b2.digest()));
+    }
+
+    #[test]
+    fn deletes() {
+        let (a, b) = (file("a", "1"), file("b", "1"));
+        // a在本地删除,b在远程删除,c两边都已删除
+        let local = HashSet::from([b.clone()]);
+        let remote = HashSet::from([a.clone()]);
+        let mut sync_base = base(&[a.clone(), b.clone()]);
+        sync_base.insert("c".into(), "x".into());
+        let plan = plan(&local, &remote, &sync_base, &[], None).unwrap();
+        assert_eq!(plan.remove_remote, ["a"]);
+        assert_eq!(plan.expected["a"], Some(a.digest()));
+        assert_eq!(plan.remove_local, ["b"]);
+        assert!(plan.push.is_empty() && plan.pull.is_empty());
+        assert_eq!(plan.base["a"], None);
+        assert_eq!(plan.base["b"], None);
+
+        // 只有记录中有的名称可以指定,都没有时报错
+        let ans = super::plan(&local, &remote, &sync_base, &["c".into()], None).unwrap();
+        assert_eq!(ans.base["c"], None);
+        assert!(super::plan(&local, &remote, &sync_base, &["x".into()], None).is_err());
+    }
+
+    #[test]
+    fn delete_and_change_conflict() {
+        // 本地删除的同时远程修改
+        let (a, a2) = (file("a", "1"), file("a", "2"));
+        let remote = HashSet::from([a2.clone()]);
+        let sync_base = base(&[a]);
+        let ans = plan(&HashSet::new(), &remote, &sync_base, &[], None).unwrap();
+        assert_eq!(ans.conflicts, ["a"]);
+
+        let ans = plan(
+            &HashSet::new(),
+            &remote,
+            &sync_base,
+            &[],
+            Some(Conflict::Local),
+        )
+        .unwrap();
+        assert_eq!(ans.remove_remote, ["a"]);
+        let ans = plan(
+            &HashSet::new(),
+            &remote,
+            &sync_base,
+            &[],
+            Some(Conflict::Both),
+        )
+        .unwrap();
+        assert_eq!(names(&ans.pull), ["a"]);
+        assert!(ans.push.is_empty());
+    }
+
+    #[test]
+    fn conflicts() {
+        let (a, l, r) = (file("a", "1"), file("a", "local"), file("a", "remote"));
+        let (local, remote, sync_base) = (
+            HashSet::from([l.clone()]),
+            HashSet::from([r.clone()]),
+            base(&[a]),
+        );
+
+        let ans = plan(&local, &remote, &sync_base, &[], None).unwrap();
+        assert_eq!(ans.conflicts, ["a"]);
+        assert!(ans.push.is_empty() && ans.pull.is_empty());
+        assert!(!ans.base.contains_key("a"));
+
+        let ans = plan(&local, &remote, &sync_base, &[], Some(Conflict::Local)).unwrap();
+        assert_eq!(digests(&ans.push), [l.digest()]);
+        assert_eq!(ans.expected["a"], Some(r.digest()));
+        assert_eq!(ans.base["a"], Some(l.digest()));
+
+        let ans = plan(&local, &remote, &sync_base, &[], Some(Conflict::Remote)).unwrap();
+        assert_eq!(digests(&ans.pull), [r.digest()]);
+        assert_eq!(ans.base["a"], Some(r.digest()));
+
+        // 两边都没有记录时同名的不同条目也是冲突
+        let ans = plan(&local, &remote, &SyncBase::new(), &[], None).unwrap();
+        assert_eq!(ans.conflicts, ["a"]);
+    }
+
+    #[test]
+    fn keep_both() {
+        let (a, l, r) = (file("a", "1"), file("a", "local"), file("a", "remote"));
+        let (local, remote, sync_base) = (
+            HashSet::from([l.clone()]),
+            HashSet::from([r.clone()]),
+            base(&[a]),
+        );
+        let ans = plan(&local, &remote, &sync_base, &[], Some(Conflict::Both)).unwrap();
+
+        // 远程版本以 `名称~摘要前8位` 保存到两边,本地版本替换远程
+        let alias = format!("a~{}", &r.digest()[..8]);
+        assert_eq!(names(&ans.push), ["a", alias.as_str()]);
+        assert_eq!(names(&ans.pull), [alias.as_str()]);
+        assert_eq!(ans.push[0].digest(), l.digest());
+        assert_eq!(ans.push[1].digest(), ans.pull[0].digest());
+        assert_eq!(ans.expected["a"], Some(r.digest()));
+        assert_eq!(ans.expected[&alias], None);
+        assert_eq!(ans.base["a"], Some(l.digest()));
+        assert_eq!(ans.base[&alias], Some(ans.pull[0].digest()));
+    }
+
+    fn copy(
+        src: &[Node],
+        dst: &[Node],
+        base: &SyncBase,
+        overwrite: Overwrite,
+    ) -> anyhow::Result<Vec<(String, Action)>> {
+        let src: HashSet<&Node> = src.iter().collect();
+        Ok(copy_plan(&src, &set(dst), base, overwrite)?
+            .into_iter()
+            .map(|c| (c.node.name, c.action))
+            .collect())
+    }
+
+    #[test]
+    fn copy_modes() {
+        let (a, b, c) = (file("a", "1"), file("b", "1"), file("c", "1"));
+        let b2 = file("b", "2");
+        let src = [a, b2.clone(), c.clone()];
+        // 目标中b与上次同步时相同,只有源修改过
+        let dst = [b.clone(), c];
+        let sync_base = base(std::slice::from_ref(&b));
+        let expected = |b: Action| {
+            vec![
+                ("a".to_string(), Action::Created),
+                ("b".to_string(), b),
+                ("c".to_string(), Action::Unchanged),
+            ]
+        };
+        for overwrite in [Overwrite::Safe, Overwrite::Force] {
+            assert_eq!(
+                copy(&src, &dst, &sync_base, overwrite).unwrap(),
+                expected(Action::Updated)
+            );
+        }
+        assert_eq!(
+            copy(&src, &dst, &sync_base, Overwrite::Never).unwrap(),
+            expected(Action::Skipped)
+        );
+
+        // 写入前比较的是计划时目标中的摘要
+        let changes = copy_plan(
+            &HashSet::from([&b2]),
+            &set(&dst),
+            &sync_base,
+            Overwrite::Safe,
+        );
+        assert_eq!(changes.unwrap()[0].expected, Some(b.digest()));
+    }
+
+    #[test]
+    fn copy_diverged() {
+        let (b, b2, b3) = (file("b", "1"), file("b", "2"), file("b", "3"));
+        // 目标在上次同步后也被修改过
+        let (src, dst, sync_base) = ([b2], [b3], base(&[b]));
+        let err = copy(&src, &dst, &sync_base, Overwrite::Safe).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+        assert_eq!(
+            copy(&src, &dst, &sync_base, Overwrite::Force).unwrap(),
+            [("b".to_string(), Action::Updated)]
+        );
+        assert_eq!(
+            copy(&src, &dst, &sync_base, Overwrite::Never).unwrap(),
+            [("b".to_string(), Action::Skipped)]
+        );
+
+        // 没有同步记录时不能判断目标是否修改过,Safe直接替换
+        assert_eq!(
+            copy(&src, &dst, &SyncBase::new(), Overwrite::Safe).unwrap(),
+            [("b".to_string(), Action::Updated)]
+        );
+    }
+}
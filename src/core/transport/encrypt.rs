@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, stdin, Cursor, IsTerminal, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use log::info;
+use md5::Digest;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use sha2::Sha256;
+
+use crate::core::config::{self, Index, Sealed};
+use crate::core::node::Node;
+use crate::core::store::Store;
+use crate::core::transport::manifest::{self, RemoteIndex};
+use crate::core::transport::Transport;
+use crate::HBX_ENCRYPTION_PASSPHRASE_ENV;
+
+/// 从口令派生密钥的迭代次数
+const KDF_ROUNDS: u32 = 200_000;
+/// 文件按块加密,每块单独认证,解密时不需要把整个文件读入内存
+const CHUNK: u64 = 64 * 1024;
+const TAG: u64 = 16;
+/// XChaCha20的nonce去掉STREAM的5字节计数器
+const STREAM_NONCE: u64 = 19;
+
+/// 加密的条目索引: 条目名称到条目清单摘要,以及每个条目清单的引用列表
+#[derive(Default, Deserialize, Serialize)]
+struct Catalog {
+    index: Index,
+    /// 条目清单摘要到引用列表的名称
+    refs: BTreeMap<String, String>,
+}
+
+/// 加密远程存储的密钥,由口令或密钥文件和远程保存的盐派生
+pub struct Keys {
+    salt: String,
+    key: [u8; 32],
+    name_key: [u8; 32],
+}
+
+impl Keys {
+    /// 读取远程索引中保存的盐,远程还没有加密数据时生成新的盐,第一次替换索引时保存
+    pub fn open(inner: &dyn Transport, secret: &[u8]) -> anyhow::Result<Self> {
+        let salt = match inner.index()?.0.sealed {
+            Some(sealed) => sealed.salt,
+            None => {
+                let mut salt = [0u8; 16];
+                OsRng.fill_bytes(&mut salt);
+                hex(&salt)
+            }
+        };
+        let mut master = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(secret, salt.as_bytes(), KDF_ROUNDS, &mut master);
+        let mut keys = Self {
+            salt,
+            key: [0; 32],
+            name_key: [0; 32],
+        };
+        keys.key.copy_from_slice(&hmac(&master, b"hbx object key"));
+        keys.name_key
+            .copy_from_slice(&hmac(&master, b"hbx name key"));
+        Ok(keys)
+    }
+
+    /// 文件在远程的名称,不知道密钥时无法判断两个远程是否保存了同一个文件。
+    /// 同一文件的名称不变,已上传的文件不会重复保存
+    fn keyed(&self, digest: &str) -> String {
+        hex(&hmac(&self.name_key, digest.as_bytes()))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+}
+
+/// 加密密钥的来源: 密钥文件的内容,或者环境变量 `HBX_ENCRYPTION_PASSPHRASE` 中的口令,未设置时在终端中询问
+pub fn secret(key_file: Option<&PathBuf>) -> anyhow::Result<Vec<u8>> {
+    if let Some(path) = key_file {
+        return fs::read(path).map_err(|e| anyhow!("read key file {:?}: {}", path, e));
+    }
+    match env::var(HBX_ENCRYPTION_PASSPHRASE_ENV) {
+        Ok(p) => Ok(p.into_bytes()),
+        Err(_) if stdin().is_terminal() => Ok(rpassword::prompt_password(
+            "Enter passphrase for the encrypted remote: ",
+        )?
+        .into_bytes()),
+        Err(_) => bail!(
+            "remote is encrypted, set {} or use --key-file",
+            HBX_ENCRYPTION_PASSPHRASE_ENV
+        ),
+    }
+}
+
+/// 加密层: 文件和条目清单在写入内层存储前加密,读取后解密,本地存储保持明文。
+/// 文件在远程以带密钥的摘要命名,密文与这个名称绑定,换成其他文件的密文时解密失败。
+/// 条目索引加密后单独保存,内层存储的索引中只有它和每个条目的引用列表,远程的gc据此保留密文
+pub struct EncryptedTransport {
+    inner: Box<dyn Transport>,
+    shared: Arc<Shared>,
+    /// 最近读取的内层索引和ETag,替换索引时保留其中的明文条目
+    last: Mutex<Option<(RemoteIndex, Option<String>)>>,
+}
+
+/// 同一远程的多个连接共享密钥和引用列表
+struct Shared {
+    keys: Keys,
+    /// 条目清单摘要到引用列表的名称
+    refs: Mutex<HashMap<String, String>>,
+}
+
+impl EncryptedTransport {
+    /// 为每个连接加上加密层,密钥只派生一次
+    pub fn wrap(
+        conns: Vec<Box<dyn Transport>>,
+        secret: &[u8],
+    ) -> anyhow::Result<Vec<Box<dyn Transport>>> {
+        let shared = Arc::new(Shared {
+            keys: Keys::open(&*conns[0], secret)?,
+            refs: Mutex::new(HashMap::new()),
+        });
+        Ok(conns
+            .into_iter()
+            .map(|inner| {
+                Box::new(Self {
+                    inner,
+                    shared: shared.clone(),
+                    last: Mutex::new(None),
+                }) as Box<dyn Transport>
+            })
+            .collect())
+    }
+
+    /// 读取并解密条目索引,记录其中的引用列表
+    fn open_catalog(&self, sealed: &Sealed) -> anyhow::Result<Catalog> {
+        if sealed.salt != self.shared.keys.salt {
+            bail!("encryption salt on the remote changed, run the command again");
+        }
+        let (_, mut reader) = self.inner.read_object(&sealed.index)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.len() < 24 {
+            bail!("invalid encrypted index");
+        }
+        let (nonce, ciphertext) = data.split_at(24);
+        let plain = self
+            .shared
+            .keys
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("decrypt remote index failed, wrong passphrase or key file"))?;
+        let catalog: Catalog = from_slice(&plain)
+            .map_err(|e| anyhow!("invalid encrypted index on the remote: {}", e))?;
+        self.shared
+            .refs
+            .lock()
+            .expect("refs poisoned")
+            .extend(catalog.refs.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Ok(catalog)
+    }
+
+    /// 加密条目索引后写入内层存储,返回它的名称
+    fn seal_catalog(&self, catalog: &Catalog) -> anyhow::Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .shared
+            .keys
+            .cipher()
+            .encrypt(&nonce, to_vec(catalog)?.as_slice())
+            .map_err(|_| anyhow!("encrypt index failed"))?;
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        let name = format!("{:x}", md5::Md5::digest(&data));
+        self.inner
+            .write_object(&name, data.len() as u64, &mut data.as_slice())?;
+        Ok(name)
+    }
+
+    /// 写入条目的引用列表: 条目清单和引用的文件的密文名称,以明文保存,远程的gc据此保留密文
+    fn write_refs(&self, digest: &str, node: &Node) -> anyhow::Result<String> {
+        let keys = &self.shared.keys;
+        let mut names: Vec<String> = Store::get_files(&mut [node].into_iter())
+            .iter()
+            .chain([&digest.to_string()])
+            .map(|d| keys.keyed(d))
+            .collect();
+        names.sort();
+        let content = to_vec(&names)?;
+        let name = format!("{:x}", md5::Md5::digest(&content));
+        self.inner
+            .write_object(&name, content.len() as u64, &mut content.as_slice())?;
+        self.shared
+            .refs
+            .lock()
+            .expect("refs poisoned")
+            .insert(digest.to_string(), name.clone());
+        Ok(name)
+    }
+
+    /// 条目清单的引用列表,不在已读取的索引中时读取条目清单后重新写入
+    fn refs(&self, digest: &str) -> anyhow::Result<String> {
+        let cached = self
+            .shared
+            .refs
+            .lock()
+            .expect("refs poisoned")
+            .get(digest)
+            .cloned();
+        match cached {
+            Some(name) => Ok(name),
+            None => self.write_refs(digest, &manifest::read_manifest(self, digest)?),
+        }
+    }
+}
+
+impl Transport for EncryptedTransport {
+    fn index(&self) -> anyhow::Result<(RemoteIndex, Option<String>)> {
+        let (inner, etag) = self.inner.index()?;
+        let catalog = match &inner.sealed {
+            Some(sealed) => self.open_catalog(sealed)?,
+            None => Catalog::default(),
+        };
+        *self.last.lock().expect("last poisoned") = Some((inner, etag.clone()));
+        Ok((
+            RemoteIndex {
+                index: catalog.index,
+                sealed: None,
+            },
+            etag,
+        ))
+    }
+
+    /// 加密新的条目索引后条件替换内层存储的索引,内层索引中的明文条目不变
+    fn swap_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool> {
+        let last = self.last.lock().expect("last poisoned").take();
+        let inner = match last {
+            Some((inner, e)) if e.as_deref() == etag => inner,
+            _ => self.inner.index()?.0,
+        };
+        let mut catalog = Catalog {
+            index: index.index.clone(),
+            refs: BTreeMap::new(),
+        };
+        for digest in index.index.values() {
+            catalog.refs.insert(digest.clone(), self.refs(digest)?);
+        }
+        let sealed = Sealed {
+            salt: self.shared.keys.salt.clone(),
+            index: self.seal_catalog(&catalog)?,
+            refs: catalog.refs.into_values().collect(),
+        };
+        let index = RemoteIndex {
+            index: inner.index,
+            sealed: Some(sealed),
+        };
+        self.inner.swap_index(&index, etag)
+    }
+
+    /// 条目清单和文件一样加密,同时写入它的引用列表
+    fn write_manifest(&self, node: &Node) -> anyhow::Result<()> {
+        let (digest, content) = config::manifest(node)?;
+        self.write_object(&digest, content.len() as u64, &mut content.as_bytes())?;
+        self.write_refs(&digest, node)?;
+        Ok(())
+    }
+
+    fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + '_>)> {
+        let aad = self.shared.keys.keyed(digest);
+        let (size, mut reader) = self.inner.read_object(&aad)?;
+        let plain = plain_size(size)?;
+        let mut nonce = [0u8; STREAM_NONCE as usize];
+        reader.read_exact(&mut nonce)?;
+        let opener = Opener {
+            inner: reader,
+            decryptor: Some(DecryptorBE32::new(
+                &self.shared.keys.key.into(),
+                nonce.as_slice().into(),
+            )),
+            aad: aad.into_bytes(),
+            remaining: size - STREAM_NONCE,
+            buf: Cursor::new(Vec::new()),
+        };
+        Ok((plain, Box::new(opener)))
+    }
+
+    /// 以带密钥的摘要命名,边加密边上传
+    fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()> {
+        let name = self.shared.keys.keyed(digest);
+        let mut nonce = [0u8; STREAM_NONCE as usize];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealer = Sealer {
+            inner: data,
+            encryptor: Some(EncryptorBE32::new(
+                &self.shared.keys.key.into(),
+                nonce.as_slice().into(),
+            )),
+            aad: name.clone().into_bytes(),
+            remaining: size,
+            buf: Cursor::new(nonce.to_vec()),
+        };
+        info!("upload {} as {}", digest, name);
+        self.inner
+            .write_object(&name, sealed_size(size), &mut sealer)
+    }
+
+    /// 密文由内层索引中的引用列表引用,可以直接由内层存储清理
+    fn gc(&self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>> {
+        self.inner.gc(dry_run, grace)
+    }
+}
+
+/// 密文大小: nonce、原文和每块的认证标签。最后一块不满CHUNK字节(可能为空),用于识别截断
+fn sealed_size(size: u64) -> u64 {
+    STREAM_NONCE + size + (size / CHUNK + 1) * TAG
+}
+
+fn plain_size(size: u64) -> anyhow::Result<u64> {
+    let body = size
+        .checked_sub(STREAM_NONCE)
+        .ok_or(anyhow!("invalid encrypted object"))?;
+    let last = (body % (CHUNK + TAG))
+        .checked_sub(TAG)
+        .ok_or(anyhow!("invalid encrypted object"))?;
+    Ok(body / (CHUNK + TAG) * CHUNK + last)
+}
+
+/// 读取原文,输出nonce和逐块加密的密文
+struct Sealer<'a> {
+    inner: &'a mut dyn Read,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    /// 每块都认证带密钥的文件摘要,密文只能按原来的摘要解密
+    aad: Vec<u8>,
+    remaining: u64,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl Read for Sealer<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buf.read(out)?;
+            if n > 0 || out.is_empty() {
+                return Ok(n);
+            }
+            let Some(mut encryptor) = self.encryptor.take() else {
+                return Ok(0);
+            };
+            let mut chunk = vec![0u8; self.remaining.min(CHUNK) as usize];
+            self.inner.read_exact(&mut chunk)?;
+            self.remaining -= chunk.len() as u64;
+            let payload = Payload {
+                msg: &chunk,
+                aad: &self.aad,
+            };
+            let sealed = if chunk.len() as u64 == CHUNK {
+                let sealed = encryptor.encrypt_next(payload);
+                self.encryptor = Some(encryptor);
+                sealed
+            } else {
+                encryptor.encrypt_last(payload)
+            };
+            let sealed = sealed.map_err(|_| io::Error::other("encrypt failed"))?;
+            self.buf = Cursor::new(sealed);
+        }
+    }
+}
+
+/// 读取密文,逐块认证并解密
+struct Opener<'a> {
+    inner: Box<dyn Read + 'a>,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    aad: Vec<u8>,
+    remaining: u64,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl Read for Opener<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buf.read(out)?;
+            if n > 0 || out.is_empty() {
+                return Ok(n);
+            }
+            let Some(mut decryptor) = self.decryptor.take() else {
+                return Ok(0);
+            };
+            // 完整的块后面至少还有最后一块的认证标签
+            let full = self.remaining >= CHUNK + 2 * TAG;
+            let mut chunk = vec![0u8; if full { CHUNK + TAG } else { self.remaining } as usize];
+            self.inner.read_exact(&mut chunk)?;
+            self.remaining -= chunk.len() as u64;
+            let payload = Payload {
+                msg: &chunk,
+                aad: &self.aad,
+            };
+            let plain = if full {
+                let plain = decryptor.decrypt_next(payload);
+                self.decryptor = Some(decryptor);
+                plain
+            } else {
+                decryptor.decrypt_last(payload)
+            };
+            let plain = plain.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decrypt object failed, wrong key or corrupted data",
+                )
+            })?;
+            self.buf = Cursor::new(plain);
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use std::env;
+use std::io::{self, ErrorKind, Read};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use log::info;
+use ureq::Response;
+
+use crate::core::node::Node;
+use crate::core::transport::manifest::{self, RemoteIndex};
+use crate::core::transport::Transport;
+use crate::HBX_HTTP_TOKEN_ENV;
+
+/// 下载中断后从断点重新请求的次数
+const MAX_RESUMES: usize = 5;
+
+/// 通过 `hbx serve --http` 访问的存储,写入时使用环境变量 HBX_HTTP_TOKEN 中的token
+pub struct HttpTransport {
+    agent: ureq::Agent,
+    base: String,
+    token: Option<String>,
+}
+
+impl HttpTransport {
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        info!("open http store {}", url);
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().build(),
+            base: url.trim_end_matches('/').to_string(),
+            token: env::var(HBX_HTTP_TOKEN_ENV).ok(),
+        })
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let req = self
+            .agent
+            .request(method, &format!("{}{}", self.base, path));
+        match &self.token {
+            Some(token) => req.set("Authorization", &format!("Bearer {}", token)),
+            None => req,
+        }
+    }
+
+    /// 读取整个配置
+    fn manifest(&self) -> anyhow::Result<HashSet<Node>> {
+        let res = check(send(self.request("GET", "/manifest"), None)?)?;
+        let mut content = String::new();
+        res.into_reader().read_to_string(&mut content)?;
+        manifest::decode(&content)
+    }
+
+    /// 读取索引和对应的ETag,没有 `/index` 的旧版本服务器返回None
+    fn get_index(&self) -> anyhow::Result<Option<(RemoteIndex, Option<String>)>> {
+        let res = send(self.request("GET", "/index"), None)?;
+        if res.status() == 404 {
+            return Ok(None);
+        }
+        let res = check(res)?;
+        let etag = res.header("ETag").map(|s| s.to_string());
+        let mut content = String::new();
+        res.into_reader().read_to_string(&mut content)?;
+        Ok(Some((manifest::decode_index(&content)?, etag)))
+    }
+
+    /// 从offset继续下载,文件在此期间发生变化时返回错误
+    fn resume(&self, digest: &str, offset: u64, etag: &str) -> anyhow::Result<Response> {
+        let req = self
+            .request("GET", &format!("/objects/{}", digest))
+            .set("Range", &format!("bytes={}-", offset))
+            .set("If-Range", etag);
+        let res = check(send(req, None)?)?;
+        if res.status() != 206 {
+            bail!("object {} changed on server while downloading", digest);
+        }
+        Ok(res)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn index(&self) -> anyhow::Result<(RemoteIndex, Option<String>)> {
+        self.get_index()?.ok_or(anyhow!(
+            "{} has no index, upgrade hbx on the server",
+            self.base
+        ))
+    }
+
+    /// 服务器上的索引与读取时不同时返回false
+    fn swap_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool> {
+        let content = manifest::encode_index(index)?;
+        let mut req = self
+            .request("PUT", "/index")
+            .set("Content-Type", "application/json");
+        if let Some(etag) = etag {
+            req = req.set("If-Match", etag);
+        }
+        let res = send(req, Some((content.len() as u64, &mut content.as_bytes())))?;
+        if res.status() == 412 {
+            return Ok(false);
+        }
+        check(res)?;
+        Ok(true)
+    }
+
+    /// 一次请求读取整个配置
+    fn config(&self) -> anyhow::Result<HashSet<Node>> {
+        self.manifest()
+    }
+
+    /// 读取索引后只下载需要的条目清单,没有 `/index` 的旧版本服务器读取整个配置
+    fn entries(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        let Some((index, _)) = self.get_index()? else {
+            info!("{} has no index, read the whole manifest", self.base);
+            let mut data = self.config()?;
+            data.retain(|node| names.contains(&node.name));
+            return Ok(data);
+        };
+        names
+            .iter()
+            .filter_map(|name| index.index.get(name))
+            .map(|digest| manifest::read_manifest(self, digest))
+            .collect()
+    }
+
+    fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + '_>)> {
+        info!("download {}/objects/{}", self.base, digest);
+        let res = check(send(
+            self.request("GET", &format!("/objects/{}", digest)),
+            None,
+        )?)?;
+        let size = res
+            .header("Content-Length")
+            .and_then(|s| s.parse().ok())
+            .ok_or(anyhow!("missing content length of object {}", digest))?;
+        let etag = res
+            .header("ETag")
+            .map(|s| s.to_string())
+            .unwrap_or(format!("\"{}\"", digest));
+        let reader = ResumableReader {
+            transport: self,
+            digest: digest.to_string(),
+            etag,
+            offset: 0,
+            size,
+            inner: res.into_reader(),
+            resumes: 0,
+        };
+        Ok((size, Box::new(reader)))
+    }
+
+    /// 服务器上已有大小相同的文件时跳过,中断后重新推送不会再次上传已完成的文件
+    fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()> {
+        let path = format!("/objects/{}", digest);
+        let head = send(self.request("HEAD", &path), None)?;
+        if head.status() == 200 && head.header("Content-Length") == Some(&size.to_string()) {
+            info!("object {} exists on server, skip", digest);
+            return Ok(());
+        }
+        info!("size {} upload {}{}", size, self.base, path);
+        let req = self
+            .request("PUT", &path)
+            .set("Content-Type", "application/octet-stream");
+        check(send(req, Some((size, data)))?)?;
+        Ok(())
+    }
+
+    /// 由服务器加锁并计算引用,需要写入token
+    fn gc(&self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>> {
+        let path = format!("/gc?grace={}&dry_run={}", grace.as_secs(), dry_run);
+        let res = check(send(self.request("POST", &path), None)?)?;
+        Ok(serde_json::from_reader(res.into_reader())?)
+    }
+}
+
+/// 下载中断时带 `Range` 和 `If-Range` 从断点重新请求
+struct ResumableReader<'a> {
+    transport: &'a HttpTransport,
+    digest: String,
+    etag: String,
+    offset: u64,
+    size: u64,
+    inner: Box<dyn Read + Send + Sync>,
+    resumes: usize,
+}
+
+impl Read for ResumableReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let err = match self.inner.read(buf) {
+                Ok(0) if self.offset < self.size => io::Error::from(ErrorKind::UnexpectedEof),
+                Ok(n) => {
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            if self.resumes >= MAX_RESUMES {
+                return Err(err);
+            }
+            self.resumes += 1;
+            info!(
+                "download {} interrupted at {} of {} bytes: {}, resume",
+                self.digest, self.offset, self.size, err
+            );
+            self.inner = self
+                .transport
+                .resume(&self.digest, self.offset, &self.etag)
+                .map_err(io::Error::other)?
+                .into_reader();
+        }
+    }
+}
+
+/// 发送请求,非2xx的响应也正常返回,由调用方检查状态码
+fn send(req: ureq::Request, body: Option<(u64, &mut dyn Read)>) -> anyhow::Result<Response> {
+    let res = match body {
+        Some((size, data)) => req
+            .set("Content-Length", &size.to_string())
+            .send(data.take(size)),
+        None => req.call(),
+    };
+    match res {
+        Ok(res) | Err(ureq::Error::Status(_, res)) => Ok(res),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 非2xx的响应转为错误
+fn check(res: Response) -> anyhow::Result<Response> {
+    if (200..300).contains(&res.status()) {
+        return Ok(res);
+    }
+    let (code, url) = (res.status(), res.get_url().to_string());
+    let text = res.into_string().unwrap_or_default();
+    bail!(
+        "request {} failed with status {}: {}",
+        url,
+        code,
+        text.trim()
+    )
+}
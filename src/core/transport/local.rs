@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::bail;
+use log::info;
+
+use crate::core::lock::FileLock;
+use crate::core::node::Node;
+use crate::core::store::Store;
+use crate::core::transport::manifest::{self, RemoteIndex};
+use crate::core::transport::Transport;
+use crate::CONFIG_NAME;
+
+/// 本地目录中的另一个hbx存储,如挂载的NAS或U盘,也可用于不依赖sshd测试同步逻辑
+pub struct LocalTransport {
+    store: Store,
+    // 持有gc读锁,传输过程中对方的delete不会清理刚写入的文件
+    gc: Mutex<Option<FileLock>>,
+}
+
+impl LocalTransport {
+    /// 只打开已有的存储,地址写错时不会在任意路径下创建新的存储
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        info!("open local store {:?}", path);
+        if !path.join(CONFIG_NAME).is_file() {
+            bail!(
+                "{:?} is not a hbx store, run `HBX_HOME={} hbx info` to create one",
+                path,
+                path.display()
+            );
+        }
+        let store = Store::new(path)?;
+        let gc = store.gc_lock()?;
+        Ok(Self {
+            store,
+            gc: Mutex::new(Some(gc)),
+        })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn config(&self) -> anyhow::Result<HashSet<Node>> {
+        let mut store = Store::new(self.store.path().to_path_buf())?;
+        store.load()?;
+        Ok(store.data().clone())
+    }
+
+    fn entries(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        Store::new(self.store.path().to_path_buf())?.entries(names)
+    }
+
+    /// 本地存储的索引保存在配置文件中,ETag由索引内容计算
+    fn index(&self) -> anyhow::Result<(RemoteIndex, Option<String>)> {
+        let index = Store::new(self.store.path().to_path_buf())?.remote_index()?;
+        let etag = manifest::index_etag(&index)?;
+        Ok((index, Some(etag)))
+    }
+
+    fn swap_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool> {
+        Store::new(self.store.path().to_path_buf())?.swap_index(index, etag)
+    }
+
+    fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + '_>)> {
+        let (size, reader) = self.store.read_object(digest)?;
+        Ok((size, reader))
+    }
+
+    fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()> {
+        self.store.write_object(digest, size, data)
+    }
+
+    /// gc需要写锁,先释放自己持有的读锁,结束后重新获取
+    fn gc(&self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut held = self.gc.lock().expect("gc lock poisoned");
+        held.take();
+        let mut store = Store::new(self.store.path().to_path_buf())?;
+        let ans = store.gc(dry_run, grace);
+        *held = Some(self.store.gc_lock()?);
+        ans
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail};
+use log::info;
+use md5::Digest;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+
+use crate::core::config::{self, Index, Sealed};
+use crate::core::node::Node;
+use crate::core::transport::Transport;
+use crate::FORMAT_VERSION;
+
+/// 条件替换冲突时重新读取索引的次数
+const MAX_RETRIES: usize = 10;
+/// 条件写入时配置或索引已被修改的错误信息,ssh远程通过它判断需要重试
+pub const CONFLICT: &str = "manifest changed";
+
+/// 对象存储和http服务中的配置文件,带格式版本号
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest<T> {
+    format_version: u32,
+    entries: T,
+}
+
+/// 按名称排序后序列化,内容相同的配置得到相同的ETag
+pub fn encode(data: &HashSet<Node>) -> anyhow::Result<String> {
+    let mut entries: Vec<&Node> = data.iter().collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(to_string(&Manifest {
+        format_version: FORMAT_VERSION,
+        entries,
+    })?)
+}
+
+/// 配置内容的摘要,用于条件写入
+pub fn etag(data: &HashSet<Node>) -> anyhow::Result<String> {
+    Ok(format!("{:x}", md5::Md5::digest(encode(data)?.as_bytes())))
+}
+
+/// 解析配置,旧版本的格式是当前格式的子集,可以直接读取,写回时使用当前版本。
+/// 更新的格式返回错误
+pub fn decode(content: &str) -> anyhow::Result<HashSet<Node>> {
+    let manifest: Manifest<HashSet<Node>> = from_str(content)?;
+    if manifest.format_version > FORMAT_VERSION {
+        bail!(
+            "remote manifest uses format {}, local hbx {} uses format {}",
+            manifest.format_version,
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION
+        );
+    }
+    Ok(manifest.entries)
+}
+
+/// 远程的条目索引,条目清单作为文件单独保存在远程。
+/// 修改条目时只写入变化的清单,再条件替换这个索引
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RemoteIndex {
+    pub index: Index,
+    /// 加密远程的条目保存在加密的索引中,不出现在index里
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Sealed>,
+}
+
+/// 带格式版本号的远程索引文档
+#[derive(Deserialize, Serialize)]
+struct IndexDocument {
+    format_version: u32,
+    #[serde(flatten)]
+    index: RemoteIndex,
+}
+
+pub fn encode_index(index: &RemoteIndex) -> anyhow::Result<String> {
+    Ok(to_string(&IndexDocument {
+        format_version: FORMAT_VERSION,
+        index: index.clone(),
+    })?)
+}
+
+/// 解析远程索引,更新的格式返回错误
+pub fn decode_index(content: &str) -> anyhow::Result<RemoteIndex> {
+    let document: IndexDocument = from_str(content)?;
+    if document.format_version > FORMAT_VERSION {
+        bail!(
+            "remote index uses format {}, local hbx {} uses format {}",
+            document.format_version,
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION
+        );
+    }
+    Ok(document.index)
+}
+
+/// 索引内容的摘要,用于条件替换
+pub fn index_etag(index: &RemoteIndex) -> anyhow::Result<String> {
+    Ok(format!(
+        "{:x}",
+        md5::Md5::digest(encode_index(index)?.as_bytes())
+    ))
+}
+
+/// 读取远程保存的条目清单
+pub fn read_manifest<T: Transport + ?Sized>(transport: &T, digest: &str) -> anyhow::Result<Node> {
+    let (_, reader) = transport
+        .read_object(digest)
+        .map_err(|e| anyhow!("read entry manifest {} failed: {}", digest, e))?;
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// 基于索引ETag的乐观锁: 读取索引和names中的条目交给f修改,写入变化的条目清单后条件替换索引,
+/// names之外的条目不变。索引在读取后被其他人修改时重新读取,已传输的条目清单不再重复传输
+pub fn update<T: Transport + ?Sized>(
+    transport: &T,
+    names: &[String],
+    f: &mut dyn FnMut(&mut HashSet<Node>),
+) -> anyhow::Result<()> {
+    // 远程已有的条目清单,按摘要保存
+    let mut manifests: HashMap<String, Node> = HashMap::new();
+    for _ in 0..MAX_RETRIES {
+        let (current, etag) = transport.index()?;
+        let mut data = HashSet::new();
+        for digest in names.iter().filter_map(|name| current.index.get(name)) {
+            if !manifests.contains_key(digest) {
+                manifests.insert(digest.clone(), read_manifest(transport, digest)?);
+            }
+            data.insert(manifests[digest].clone());
+        }
+        f(&mut data);
+
+        let mut index = current.clone();
+        for name in names {
+            index.index.remove(name);
+        }
+        for node in &data {
+            if !names.contains(&node.name) {
+                bail!("entry {} is not being updated", node.name);
+            }
+            let (digest, _) = config::manifest(node)?;
+            if !manifests.contains_key(&digest) {
+                transport.write_manifest(node)?;
+                manifests.insert(digest.clone(), node.clone());
+            }
+            index.index.insert(node.name.clone(), digest);
+        }
+        if index == current || transport.swap_index(&index, etag.as_deref())? {
+            return Ok(());
+        }
+        info!("remote index changed concurrently, retry");
+    }
+    bail!("update remote index failed after {} retries", MAX_RETRIES)
+}
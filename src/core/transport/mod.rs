@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::core::cli::{EncryptArgs, InstallArgs, SshArgs};
+use crate::core::config;
+use crate::core::node::Node;
+use crate::core::transport::encrypt::EncryptedTransport;
+use crate::core::transport::http::HttpTransport;
+use crate::core::transport::local::LocalTransport;
+use crate::core::transport::manifest::RemoteIndex;
+use crate::core::transport::s3::S3Transport;
+use crate::core::transport::ssh::SshTransport;
+
+pub mod encrypt;
+pub mod http;
+pub mod local;
+pub mod manifest;
+pub mod s3;
+pub mod ssh;
+
+/// 远程存储,push和pull通过它读写远程的配置和文件,差异计算和合并由Store完成
+pub trait Transport: Send + Sync {
+    /// 读取远程的条目索引和它的ETag,远程还没有索引时返回空索引和None
+    fn index(&self) -> anyhow::Result<(RemoteIndex, Option<String>)>;
+
+    /// 条件替换远程的条目索引: etag为None时要求远程还没有索引,否则要求索引的ETag不变。
+    /// 索引引用的条目清单需已写入远程,条件不满足时返回false
+    fn swap_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool>;
+
+    /// 读取远程配置
+    fn config(&self) -> anyhow::Result<HashSet<Node>> {
+        let (index, _) = self.index()?;
+        index
+            .index
+            .values()
+            .map(|digest| manifest::read_manifest(self, digest))
+            .collect()
+    }
+
+    /// 只读取远程配置中指定名称的条目,不存在的名称忽略。
+    /// 条目清单单独保存,只下载需要的条目
+    fn entries(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        let (index, _) = self.index()?;
+        names
+            .iter()
+            .filter_map(|name| index.index.get(name))
+            .map(|digest| manifest::read_manifest(self, digest))
+            .collect()
+    }
+
+    /// 写入条目清单,清单以内容的摘要命名
+    fn write_manifest(&self, node: &Node) -> anyhow::Result<()> {
+        let (digest, content) = config::manifest(node)?;
+        self.write_object(&digest, content.len() as u64, &mut content.as_bytes())
+    }
+
+    /// 修改names中的条目: 写入变化的条目清单后条件替换远程索引,
+    /// 索引在此期间被修改时重新读取,避免并发修改互相覆盖。f只能修改names中的条目
+    fn update_entries(
+        &self,
+        names: &[String],
+        f: &mut dyn FnMut(&mut HashSet<Node>),
+    ) -> anyhow::Result<()> {
+        manifest::update(self, names, f)
+    }
+
+    /// 打开远程文件,返回文件大小和读取流
+    fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + '_>)>;
+
+    /// 写入size字节到远程文件
+    fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()>;
+
+    /// 在远程删除没有被远程配置引用的文件,dry_run时只列出,grace时间内写入的文件保留。
+    /// 返回文件摘要和大小
+    fn gc(&self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>>;
+}
+
+/// 远程存储的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportKind {
+    /// hbx on a server reached over ssh
+    Ssh,
+    /// another hbx home on a local or mounted path
+    Local,
+    /// S3-compatible object storage
+    S3,
+    /// a store served by hbx serve --http
+    Http,
+}
+
+impl TransportKind {
+    /// `s3://bucket/prefix` 是S3兼容的对象存储,`http://` 和 `https://` 是 `hbx serve --http` 提供的存储,
+    /// `file://` 开头或包含 `/` 的地址是本地目录(如挂载的NAS或U盘上的HBX_HOME),其他为ssh地址
+    pub fn detect(address: &str) -> Self {
+        if address.starts_with("s3://") {
+            Self::S3
+        } else if address.starts_with("http://") || address.starts_with("https://") {
+            Self::Http
+        } else if address.starts_with("file://")
+            || (!address.contains("://") && address.contains('/'))
+        {
+            Self::Local
+        } else {
+            Self::Ssh
+        }
+    }
+}
+
+/// 根据地址建立jobs个连接,用于并行传输。未指定类型时根据地址判断,
+/// 指定加密时在连接外加上加密层
+pub fn connect(
+    address: &str,
+    kind: Option<TransportKind>,
+    ssh: &SshArgs,
+    install: &InstallArgs,
+    encryption: &EncryptArgs,
+    jobs: usize,
+) -> anyhow::Result<Vec<Box<dyn Transport>>> {
+    let conns = open(address, kind, ssh, install, jobs)?;
+    if !encryption.enabled() {
+        return Ok(conns);
+    }
+    let secret = encrypt::secret(encryption.key_file.as_ref())?;
+    EncryptedTransport::wrap(conns, &secret)
+}
+
+fn open(
+    address: &str,
+    kind: Option<TransportKind>,
+    ssh: &SshArgs,
+    install: &InstallArgs,
+    jobs: usize,
+) -> anyhow::Result<Vec<Box<dyn Transport>>> {
+    let jobs = jobs.max(1);
+    let open =
+        |f: &dyn Fn() -> anyhow::Result<Box<dyn Transport>>| (0..jobs).map(|_| f()).collect();
+    match kind.unwrap_or_else(|| TransportKind::detect(address)) {
+        TransportKind::S3 => open(&|| Ok(Box::new(S3Transport::open(address)?))),
+        TransportKind::Http => open(&|| Ok(Box::new(HttpTransport::open(address)?))),
+        TransportKind::Local => {
+            let path = PathBuf::from(address.strip_prefix("file://").unwrap_or(address));
+            open(&|| Ok(Box::new(LocalTransport::open(path.clone())?)))
+        }
+        TransportKind::Ssh => Ok(SshTransport::connect(address, ssh, install, jobs)?
+            .into_iter()
+            .map(|t| Box::new(t) as Box<dyn Transport>)
+            .collect()),
+    }
+}
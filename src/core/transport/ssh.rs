@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use log::info;
+use serde_json::from_str;
+
+use crate::core::address::Target;
+use crate::core::agent::Agent;
+use crate::core::auth::Auth;
+use crate::core::cli::{InstallArgs, SshArgs};
+use crate::core::compress::{self, ZSTD_SUFFIX};
+use crate::core::install;
+use crate::core::layout::Layout;
+use crate::core::node::Node;
+use crate::core::transport::manifest::{self, RemoteIndex};
+use crate::core::transport::Transport;
+use crate::core::util::quote;
+use crate::FORMAT_VERSION;
+
+/// 通过ssh访问服务器上的hbx存储,存储目录的位置由服务器上的 `hbx info` 给出,
+/// 配置由服务器上的hbx读写
+pub struct SshTransport {
+    agent: Agent,
+    hbx: String,
+    storage: PathBuf,
+    /// 服务器上存储目录的布局
+    layout: Layout,
+}
+
+impl SshTransport {
+    /// 建立jobs个会话,第一个会话检查服务器上的hbx
+    pub fn connect(
+        address: &str,
+        ssh: &SshArgs,
+        install: &InstallArgs,
+        jobs: usize,
+    ) -> anyhow::Result<Vec<Self>> {
+        let target = Target::resolve(address, ssh.port, ssh.host_key_checking)?;
+        let mut auth = Auth::new(&ssh.identities, ssh.password, &target);
+        info!(
+            "username : {}, host: {}:{}",
+            target.user, target.host, target.port
+        );
+
+        let mut ans = Vec::new();
+        let mut info: Option<(String, HashMap<String, String>)> = None;
+        for _ in 0..jobs {
+            // 登陆远程服务器
+            let mut agent = Agent::new()?;
+            agent.set_compress(ssh.compress);
+            agent.login(&target, &mut auth)?;
+            let (hbx, map) = match &info {
+                Some(info) => info,
+                None => info.insert(Self::remote_hbx(&agent, ssh.hbx_path.as_deref(), install)?),
+            };
+            let storage = map.get("storage").ok_or(anyhow!("storage info error"))?;
+            // 较旧的hbx只有flat布局
+            let layout = match map.get("layout") {
+                Some(layout) => Layout::from_str(layout, true).map_err(|e| anyhow!(e))?,
+                None => Layout::Flat,
+            };
+            ans.push(Self {
+                agent,
+                hbx: hbx.clone(),
+                storage: storage.into(),
+                layout,
+            });
+        }
+        Ok(ans)
+    }
+
+    /// 查找服务器上的hbx并读取其信息,未安装或版本不兼容时根据--install安装或升级。
+    /// 指定了hbx_path时不再查找,返回hbx的路径和信息
+    fn remote_hbx(
+        agent: &Agent,
+        hbx_path: Option<&str>,
+        install: &InstallArgs,
+    ) -> anyhow::Result<(String, HashMap<String, String>)> {
+        let do_install = |path: Option<&str>| {
+            install::install(
+                agent,
+                install.binaries.as_deref(),
+                install.install_path.as_deref().or(path),
+            )
+        };
+        let found = match hbx_path {
+            Some(path) => {
+                let (code, _) = agent.execute_with_status(&format!("[ -x {} ]", quote(path)))?;
+                (code == 0).then(|| path.to_string())
+            }
+            None => install::find(agent)?,
+        };
+        let hbx = match found {
+            Some(hbx) => hbx,
+            None if install.install => do_install(hbx_path)?,
+            None => bail!("remote server not install hbx, use --install to install it"),
+        };
+        let map = Self::remote_hbx_info(agent, &hbx)?;
+        match Self::check_compatible(&map) {
+            Ok(()) => Ok((hbx, map)),
+            Err(e) if install.install && Self::remote_format(&map) < FORMAT_VERSION => {
+                info!("{}, upgrade remote hbx", e);
+                let hbx = do_install(Some(&hbx))?;
+                let map = Self::remote_hbx_info(agent, &hbx)?;
+                Self::check_compatible(&map)?;
+                Ok((hbx, map))
+            }
+            Err(e) if Self::remote_format(&map) < FORMAT_VERSION => {
+                bail!("{}, use --install to upgrade the remote hbx", e)
+            }
+            Err(e) => bail!("{}, please upgrade the local hbx", e),
+        }
+    }
+
+    fn remote_hbx_info(agent: &Agent, hbx: &str) -> anyhow::Result<HashMap<String, String>> {
+        let (code, info) = agent.execute_with_status(&format!("{} info 2>&1", quote(hbx)))?;
+        let info = info.trim();
+        info!("remote info: {}", info);
+        if code != 0 {
+            bail!("run {} info on server failed: {}", hbx, info);
+        }
+        // 日志输出在前,信息在最后一行
+        let line = info.lines().last().unwrap_or_default();
+        from_str::<HashMap<String, String>>(line)
+            .map_err(|e| anyhow!("invalid output of {} info: {}", hbx, e))
+    }
+
+    /// 没有format_version字段的hbx使用第一版配置格式
+    fn remote_format(map: &HashMap<String, String>) -> u32 {
+        map.get("format_version")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1)
+    }
+
+    /// 配置格式相同才能互相同步
+    fn check_compatible(map: &HashMap<String, String>) -> anyhow::Result<()> {
+        let format = Self::remote_format(map);
+        let version = map.get("version").map(|v| v.as_str()).unwrap_or("unknown");
+        if format != FORMAT_VERSION {
+            bail!(
+                "remote hbx {} uses config format {}, local hbx {} uses format {}",
+                version,
+                format,
+                env!("CARGO_PKG_VERSION"),
+                FORMAT_VERSION
+            );
+        }
+        if version != env!("CARGO_PKG_VERSION") {
+            info!(
+                "remote hbx {} differs from local {}, config format {} is compatible",
+                version,
+                env!("CARGO_PKG_VERSION"),
+                format
+            );
+        }
+        Ok(())
+    }
+
+    /// 在服务器上运行 `hbx export`,只读取指定名称的条目清单,未指定名称时输出所有条目
+    fn export(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        let mut cmd = format!("{} export", quote(&self.hbx));
+        for name in names {
+            cmd.push(' ');
+            cmd.push_str(&quote(name));
+        }
+        // 日志输出到标准错误,标准输出只有配置
+        let (code, out, err) = self.agent.execute_with_stderr(&cmd)?;
+        if code != 0 {
+            bail!("run {} on server failed: {}", cmd, err.trim());
+        }
+        manifest::decode(out.trim())
+    }
+
+    /// 在服务器上运行 `hbx import --index`,由服务器上的hbx加锁,
+    /// 索引与etag不同时返回false
+    fn import_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool> {
+        let mut cmd = format!("{} import --index", quote(&self.hbx));
+        if let Some(etag) = etag {
+            cmd.push_str(&format!(" --if-match {}", quote(etag)));
+        }
+        let (code, _, err) = self
+            .agent
+            .execute_with_input(&cmd, &manifest::encode_index(index)?)?;
+        match code {
+            0 => Ok(true),
+            _ if err.contains(manifest::CONFLICT) => Ok(false),
+            _ => bail!(
+                "run {} on server failed: {}, the remote hbx may need an upgrade with --install",
+                cmd,
+                err.trim()
+            ),
+        }
+    }
+}
+
+impl Transport for SshTransport {
+    /// 读取时计算索引的摘要,替换时由服务器上的hbx比较
+    fn index(&self) -> anyhow::Result<(RemoteIndex, Option<String>)> {
+        let cmd = format!("{} export --index", quote(&self.hbx));
+        let (code, out, err) = self.agent.execute_with_stderr(&cmd)?;
+        if code != 0 {
+            bail!(
+                "run {} on server failed: {}, the remote hbx may need an upgrade with --install",
+                cmd,
+                err.trim()
+            );
+        }
+        let index = manifest::decode_index(out.trim())?;
+        let etag = manifest::index_etag(&index)?;
+        Ok((index, Some(etag)))
+    }
+
+    fn swap_index(&self, index: &RemoteIndex, etag: Option<&str>) -> anyhow::Result<bool> {
+        self.import_index(index, etag)
+    }
+
+    /// 由服务器上的hbx读取所有条目清单,一次输出
+    fn config(&self) -> anyhow::Result<HashSet<Node>> {
+        self.export(&[])
+    }
+
+    fn entries(&self, names: &[String]) -> anyhow::Result<HashSet<Node>> {
+        if names.is_empty() {
+            return Ok(HashSet::new());
+        }
+        self.export(names)
+    }
+
+    /// 服务器上的存储设置了压缩时文件以 `<digest>.zst` 保存,下载后在本地解压
+    fn read_object(&self, digest: &str) -> anyhow::Result<(u64, Box<dyn Read + '_>)> {
+        let remote = self.storage.join(self.layout.path(digest));
+        info!("download {:?}", remote);
+        match self.agent.reader(&remote) {
+            Ok((size, reader)) => Ok((size, Box::new(reader))),
+            Err(e) => {
+                let compressed = self
+                    .storage
+                    .join(self.layout.path(&format!("{}{}", digest, ZSTD_SUFFIX)));
+                let Ok((_, reader)) = self.agent.reader(&compressed) else {
+                    return Err(e);
+                };
+                let (size, reader) = compress::decompress(reader)?;
+                Ok((size, Box::new(reader)))
+            }
+        }
+    }
+
+    /// fanout布局的子目录已由服务器上的 `hbx migrate` 创建。
+    /// 先上传到存储目录中的临时文件,完整后再重命名,中断的传输不会留下以摘要命名的不完整文件,
+    /// 残留的临时文件由gc清理
+    fn write_object(&self, digest: &str, size: u64, data: &mut dyn Read) -> anyhow::Result<()> {
+        let remote = self.storage.join(self.layout.path(digest));
+        let tmp = self
+            .storage
+            .join(format!(".tmp-{}-{}", digest, std::process::id()));
+        info!("size {} upload {:?}", size, remote);
+        self.agent.write(&tmp, 0o755, size, data)?;
+        let (tmp, remote) = (tmp.to_string_lossy(), remote.to_string_lossy());
+        let cmd = format!("mv -f {} {}", quote(&tmp), quote(&remote));
+        let (code, _, err) = self.agent.execute_with_stderr(&cmd)?;
+        if code != 0 {
+            bail!(
+                "move {} to {} on server failed: {}",
+                tmp,
+                remote,
+                err.trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// 在服务器上运行 `hbx gc`,由服务器上的hbx加锁并计算引用
+    fn gc(&self, dry_run: bool, grace: Duration) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut cmd = format!("{} gc --grace {}", quote(&self.hbx), grace.as_secs());
+        if dry_run {
+            cmd.push_str(" --dry-run");
+        }
+        let (code, out) = self.agent.execute_with_status(&format!("{} 2>&1", cmd))?;
+        if code != 0 {
+            bail!(
+                "run {} on server failed: {}, the remote hbx may need an upgrade with --install",
+                cmd,
+                out.trim()
+            );
+        }
+        // 每个文件一行 `<digest>\t<size>`,日志和汇总行中没有制表符
+        Ok(out
+            .lines()
+            .filter_map(|line| {
+                let (digest, size) = line.split_once('\t')?;
+                Some((digest.to_string(), size.trim().parse().ok()?))
+            })
+            .collect())
+    }
+}
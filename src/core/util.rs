@@ -1,6 +1,14 @@
+use std::fmt::{Display, Formatter};
 use std::fs;
+use std::io::{self, Read};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
+use anyhow::bail;
+use log::info;
 use md5::Digest;
 
 pub fn md5(path: &Path) -> anyhow::Result<String> {
@@ -10,3 +18,196 @@ pub fn md5(path: &Path) -> anyhow::Result<String> {
     let hash = hasher.finalize();
     Ok(format!("{:x}", hash))
 }
+
+/// 转义为shell单引号字符串
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// 每个连接一个线程,从共享队列中取任务执行。
+/// 所有任务都会被尝试,失败的任务汇总后一起返回错误
+pub fn parallel<C, F>(conns: &[C], items: Vec<String>, f: F) -> anyhow::Result<()>
+where
+    C: Sync,
+    F: Fn(&C, &str) -> anyhow::Result<()> + Sync,
+{
+    let total = items.len();
+    let queue = Mutex::new(items);
+    let errors = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for conn in conns {
+            s.spawn(|| loop {
+                let item = match queue.lock().unwrap().pop() {
+                    None => break,
+                    Some(item) => item,
+                };
+                if let Err(e) = f(conn, &item) {
+                    info!("{} failed: {}", item, e);
+                    errors.lock().unwrap().push(format!("{}: {}", item, e));
+                }
+            });
+        }
+    });
+    let errors = errors.into_inner().unwrap();
+    if !errors.is_empty() {
+        bail!(
+            "{} of {} transfers failed:\n{}",
+            errors.len(),
+            total,
+            errors.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// 统计传输的字节数,设置了limit时限制所有连接合计的速度,单位字节/秒
+pub struct Meter {
+    limit: Option<u64>,
+    start: Instant,
+    bytes: AtomicU64,
+}
+
+impl Meter {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            start: Instant::now(),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// 包装读取流,读取的字节计入统计并按限速等待
+    pub fn reader<'a>(&'a self, inner: &'a mut dyn Read) -> MeterReader<'a> {
+        MeterReader { meter: self, inner }
+    }
+
+    fn add(&self, n: u64) {
+        let total = self.bytes.fetch_add(n, Ordering::Relaxed) + n;
+        if let Some(limit) = self.limit.filter(|l| *l > 0) {
+            let expected = Duration::from_secs_f64(total as f64 / limit as f64);
+            let elapsed = self.start.elapsed();
+            if expected > elapsed {
+                sleep(expected - elapsed);
+            }
+        }
+    }
+}
+
+/// 传输量、用时和平均速度
+impl Display for Meter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let secs = self.start.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+        write!(
+            f,
+            "transferred {} in {:.1}s ({}/s)",
+            human_size(bytes as f64),
+            secs,
+            human_size(rate)
+        )
+    }
+}
+
+pub struct MeterReader<'a> {
+    meter: &'a Meter,
+    inner: &'a mut dyn Read,
+}
+
+impl Read for MeterReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.meter.add(n as u64);
+        Ok(n)
+    }
+}
+
+/// 解析 `500K`、`2M`、`1G` 格式的速度,单位字节/秒
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "rate")
+}
+
+/// 解析 `64M`、`1G` 格式的大小,单位字节
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "size")
+}
+
+fn parse_bytes(s: &str, what: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("invalid {} unit {}, use K, M or G", what, unit)),
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid {} {}", what, s))?;
+    if num <= 0.0 {
+        return Err(format!("{} must be positive: {}", what, s));
+    }
+    // 小于1字节的值取整后为0,限速时会变成不限速
+    let bytes = (num * unit as f64) as u64;
+    if bytes < 1 {
+        return Err(format!("{} must be at least 1 byte: {}", what, s));
+    }
+    Ok(bytes)
+}
+
+fn human_size(bytes: f64) -> String {
+    let mut size = bytes;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} TiB", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("100"), Ok(100));
+        assert_eq!(parse_rate("10B"), Ok(10));
+        assert_eq!(parse_rate("500K"), Ok(500 << 10));
+        assert_eq!(parse_rate("1.5k"), Ok(1536));
+        assert_eq!(parse_rate(" 2 MB "), Ok(2 << 20));
+        assert_eq!(parse_rate("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        for invalid in ["", "abc", "5X", "1T", "0", "-1", "0.5", "0.0001K"] {
+            assert!(parse_rate(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_rate("0.5").unwrap_err().contains("at least 1 byte"));
+    }
+
+    #[test]
+    fn meter_limits_rate() {
+        let read = |meter: &Meter, size: usize| {
+            let data = vec![0u8; size];
+            let mut inner = data.as_slice();
+            io::copy(&mut meter.reader(&mut inner), &mut io::sink()).unwrap();
+        };
+
+        let meter = Meter::new(None);
+        read(&meter, 1 << 20);
+        assert_eq!(meter.bytes.load(Ordering::Relaxed), 1 << 20);
+        assert!(meter.start.elapsed() < Duration::from_millis(200));
+        assert!(meter.to_string().starts_with("transferred 1.0 MiB"));
+
+        // 10KiB/s传输3KiB至少需要0.3秒
+        let meter = Meter::new(Some(10 << 10));
+        read(&meter, 3 << 10);
+        assert!(meter.start.elapsed() >= Duration::from_millis(290));
+        assert!(meter.start.elapsed() < Duration::from_secs(2));
+    }
+}
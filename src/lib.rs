@@ -1,17 +1,69 @@
-use clap::Parser;
+use std::env;
+use std::fs::read_to_string;
+use std::io;
 
-use crate::core::cli::Commands;
+use anyhow::{anyhow, bail};
+use clap::{Parser, ValueEnum};
+
+use serde_json::json;
+
+#[cfg(feature = "sqlite")]
+use crate::core::cli::IndexCommands;
+use crate::core::cli::{Commands, EncryptArgs, InstallArgs, Output, RemoteCommands, SshArgs};
+use crate::core::node::Node;
+use crate::core::remote::{self, Remote};
+use crate::core::store::Store;
+use crate::core::transport::{manifest, Transport, TransportKind};
+use crate::core::{server, transport};
 
 pub mod core;
 
 pub const HBX_HOME_ENV: &str = "HBX_HOME";
 pub const CONFIG_NAME: &str = "config";
+/// 转换为当前格式前的配置文件备份
+pub const CONFIG_BACKUP_NAME: &str = "config.bak";
 pub const STORE_DIRECTORY: &str = "store";
+/// 配置文件格式版本,格式不同的hbx之间不能同步。没有版本号的条目数组为格式1,
+/// 5: 配置文件为带格式版本、存储设置和条目索引的文档,每个条目的清单单独保存,
+/// 存储中的文件可以切分或压缩保存
+pub const FORMAT_VERSION: u32 = 5;
+/// 每个远程上次同步后的条目摘要
+pub const SYNC_STATE_NAME: &str = "sync";
+/// 可选的SQLite索引,需要编译时启用sqlite特性
+pub const INDEX_DATABASE_NAME: &str = "index.db";
+pub const CONFIG_LOCK_NAME: &str = "config.lock";
+pub const GC_LOCK_NAME: &str = "gc.lock";
+/// 等待本地锁的超时时间,单位秒
+pub const HBX_LOCK_TIMEOUT_ENV: &str = "HBX_LOCK_TIMEOUT";
+/// 私钥口令,未设置时在终端中询问
+pub const HBX_SSH_PASSPHRASE_ENV: &str = "HBX_SSH_PASSPHRASE";
+/// 服务器登录密码,需同时指定 --password
+pub const HBX_SSH_PASSWORD_ENV: &str = "HBX_SSH_PASSWORD";
+/// `hbx serve --http` 的写入token,客户端推送时也从这里读取
+pub const HBX_HTTP_TOKEN_ENV: &str = "HBX_HTTP_TOKEN";
+/// 加密远程存储的口令,未设置且没有指定密钥文件时在终端中询问
+pub const HBX_ENCRYPTION_PASSPHRASE_ENV: &str = "HBX_ENCRYPTION_PASSPHRASE";
 
 pub fn run() -> anyhow::Result<()> {
-    let mut store = core::store::Store::default()?;
-    store.load()?;
+    let mut store = Store::default()?;
     let cli = core::cli::Cli::parse();
+    // 这些命令只需要配置文件中的设置和索引,或自行读取条目
+    let reads_entries = match cli.command {
+        Commands::List { .. }
+        | Commands::Info { .. }
+        | Commands::Config { .. }
+        | Commands::Migrate { .. }
+        | Commands::Gc { .. }
+        | Commands::Export { .. }
+        | Commands::Import { .. }
+        | Commands::Serve { .. } => false,
+        #[cfg(feature = "sqlite")]
+        Commands::Index { .. } => false,
+        _ => true,
+    };
+    if reads_entries {
+        store.load()?;
+    }
     match cli.command {
         Commands::Add { path } => {
             store.add(&path)?;
@@ -22,31 +74,463 @@ pub fn run() -> anyhow::Result<()> {
         Commands::Delete { name } => {
             store.delete(&name)?;
         }
-        Commands::List { .. } => {
-            for item in store.list() {
-                println!("{}", item);
+        Commands::List { .. } => match cli.output {
+            Output::Text => {
+                for item in store.list() {
+                    println!("{}", item);
+                }
             }
+            Output::Json => println!("{}", serde_json::to_string(&store.list())?),
+        },
+        Commands::Info { sizes } => {
+            println!("{}", store.info(sizes)?);
         }
-        Commands::Info { .. } => {
-            println!("{}", store.info()?);
+        Commands::Config {
+            compression,
+            chunk_threshold,
+            no_chunking,
+        } => {
+            store.update_settings(|settings| {
+                if let Some(compression) = compression {
+                    settings.compression = compression;
+                }
+                if chunk_threshold.is_some() || no_chunking {
+                    settings.chunk_threshold = chunk_threshold;
+                }
+            })?;
+            println!("{}", serde_json::to_string(store.settings())?);
+        }
+        Commands::Migrate { layout } => {
+            let moved = store.migrate(layout)?;
+            if let Some(name) = layout.to_possible_value() {
+                println!("moved {} objects to the {} layout", moved, name.get_name());
+            }
+        }
+        Commands::Export { names, index } => {
+            if index {
+                println!("{}", manifest::encode_index(&store.remote_index()?)?);
+            } else {
+                let data = if names.is_empty() {
+                    store.load()?;
+                    store.data().clone()
+                } else {
+                    store.entries(&names)?
+                };
+                println!("{}", manifest::encode(&data)?);
+            }
+        }
+        Commands::Import {
+            file,
+            if_match,
+            index,
+        } => {
+            let content = match file {
+                Some(file) => read_to_string(file)?,
+                None => io::read_to_string(io::stdin())?,
+            };
+            let (imported, count) = if index {
+                let index = manifest::decode_index(&content)?;
+                let count = index.index.len();
+                (store.swap_index(&index, if_match.as_deref())?, count)
+            } else {
+                let data = manifest::decode(&content)?;
+                let count = data.len();
+                (store.import(data, if_match.as_deref())?, count)
+            };
+            if !imported {
+                bail!(
+                    "{} since {}",
+                    manifest::CONFLICT,
+                    if_match.unwrap_or_default()
+                );
+            }
+            println!("imported {} entries", count);
+        }
+        Commands::Gc { dry_run, gc } => {
+            report_gc(&store.gc(dry_run, gc.grace())?, dry_run, cli.output)?;
         }
         Commands::Pull {
             address,
-            names,
-            port,
+            mut names,
             all,
+            jobs,
+            transfer,
+            mut transport,
+            overwrite,
+            mut ssh,
+            mut encryption,
+            install,
         } => {
-            store.pull(address, names, port, all)?;
+            let address = remote::resolve(
+                &store.remotes()?,
+                &address,
+                &mut names,
+                all,
+                &mut ssh,
+                &mut transport,
+                &mut encryption,
+            );
+            let remotes =
+                transport::connect(&address, transport, &ssh, &install, &encryption, jobs)?;
+            store.pull(
+                &remotes,
+                &address,
+                names,
+                all,
+                overwrite.mode(),
+                transfer.limit_rate,
+            )?;
         }
         Commands::Push {
             address,
-            names,
-            port,
+            mut names,
+            all,
+            jobs,
+            transfer,
+            mut transport,
+            overwrite,
+            prune,
+            gc,
+            mut ssh,
+            mut encryption,
             install,
+        } => {
+            let address = remote::resolve(
+                &store.remotes()?,
+                &address,
+                &mut names,
+                all,
+                &mut ssh,
+                &mut transport,
+                &mut encryption,
+            );
+            let mut remotes =
+                transport::connect(&address, transport, &ssh, &install, &encryption, jobs)?;
+            store.push(
+                &remotes,
+                &address,
+                names,
+                all,
+                overwrite.mode(),
+                transfer.limit_rate,
+            )?;
+            if prune {
+                // 其他连接持有的锁会阻塞远程的gc
+                remotes.truncate(1);
+                report_gc(&remotes[0].gc(false, gc.grace())?, false, cli.output)?;
+            }
+        }
+        Commands::Relay {
+            src,
+            dst,
+            mut names,
             all,
+            jobs,
+            transfer,
+            overwrite,
+            encryption,
+            ssh,
+            install,
+        } => {
+            let remotes = store.remotes()?;
+            let (mut src_ssh, mut src_transport) = (ssh.clone(), None);
+            let mut src_encryption = encryption.src();
+            let src = remote::resolve(
+                &remotes,
+                &src,
+                &mut names,
+                all,
+                &mut src_ssh,
+                &mut src_transport,
+                &mut src_encryption,
+            );
+            let (mut dst_ssh, mut dst_transport) = (ssh, None);
+            let mut dst_encryption = encryption.dst();
+            let dst = remote::resolve(
+                &remotes,
+                &dst,
+                &mut Vec::new(),
+                true,
+                &mut dst_ssh,
+                &mut dst_transport,
+                &mut dst_encryption,
+            );
+            let src = transport::connect(
+                &src,
+                src_transport,
+                &src_ssh,
+                &install,
+                &src_encryption,
+                jobs,
+            )?;
+            let dst = transport::connect(
+                &dst,
+                dst_transport,
+                &dst_ssh,
+                &install,
+                &dst_encryption,
+                jobs,
+            )?;
+            Store::relay(
+                &src,
+                &dst,
+                names,
+                all,
+                overwrite.mode(),
+                transfer.limit_rate,
+            )?;
+        }
+        Commands::Sync {
+            address,
+            mut names,
+            conflict,
+            jobs,
+            transfer,
+            mut transport,
+            mut ssh,
+            mut encryption,
+            install,
+        } => {
+            let address = remote::resolve(
+                &store.remotes()?,
+                &address,
+                &mut names,
+                false,
+                &mut ssh,
+                &mut transport,
+                &mut encryption,
+            );
+            let remotes =
+                transport::connect(&address, transport, &ssh, &install, &encryption, jobs)?;
+            store.sync(&remotes, &address, names, conflict, transfer.limit_rate)?;
+        }
+        Commands::Remote { command } => remote_command(&store, command, cli.output)?,
+        Commands::Serve { http, token } => {
+            let token = token.or(env::var(HBX_HTTP_TOKEN_ENV).ok());
+            server::serve(store.path(), &http, token)?;
+        }
+        #[cfg(feature = "sqlite")]
+        Commands::Index { command } => index_command(&mut store, command, cli.output)?,
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn index_command(store: &mut Store, command: IndexCommands, output: Output) -> anyhow::Result<()> {
+    match command {
+        IndexCommands::Import {} => {
+            let count = store.create_database()?;
+            println!("indexed {} entries", count);
+        }
+        IndexCommands::Export { names } => {
+            let data = store.database()?.entries(&names)?;
+            println!("{}", manifest::encode(&data)?);
+        }
+        IndexCommands::Refs { digest } => {
+            let refs = store.database()?.refs(&digest)?;
+            match output {
+                Output::Text => {
+                    for (entry, path) in &refs {
+                        println!("{}\t{}", entry, path);
+                    }
+                }
+                Output::Json => {
+                    let refs: Vec<_> = refs
+                        .iter()
+                        .map(|(entry, path)| json!({"entry": entry, "path": path}))
+                        .collect();
+                    println!("{}", serde_json::to_string(&refs)?);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remote_command(store: &Store, command: RemoteCommands, output: Output) -> anyhow::Result<()> {
+    match command {
+        RemoteCommands::Add {
+            name,
+            url,
+            port,
+            identities,
+            transport,
+            hbx_path,
+            entries,
+            encryption,
+        } => store.update_remotes(|remotes| {
+            if remotes.contains_key(&name) {
+                bail!("remote {} already exists", name);
+            }
+            let remote = Remote {
+                url,
+                port,
+                identities,
+                transport,
+                hbx_path,
+                entries,
+                encrypt: encryption.encrypt,
+                key_file: encryption.key_file,
+            };
+            remotes.insert(name, remote);
+            Ok(())
+        })?,
+        RemoteCommands::Remove { name } => store.update_remotes(|remotes| {
+            remotes
+                .remove(&name)
+                .ok_or(anyhow!("remote {} not found", name))?;
+            Ok(())
+        })?,
+        RemoteCommands::List {} => {
+            for (name, remote) in store.remotes()? {
+                println!("{}\t{}", name, remote);
+            }
+        }
+        RemoteCommands::Show {
+            name,
+            mut ssh,
+            mut encryption,
+            install,
+        } => {
+            let remotes = store.remotes()?;
+            let remote = remotes
+                .get(&name)
+                .ok_or(anyhow!("remote {} not found", name))?;
+            let mut transport = None;
+            remote.apply(&mut ssh, &mut transport, &mut encryption);
+            let conn = transport::connect(&remote.url, transport, &ssh, &install, &encryption, 1)?;
+            println!("{}\t{}", name, remote);
+            for (entry, status) in remote::compare(store.data(), &conn[0].config()?) {
+                println!("{:<10} {}", status.to_string(), entry);
+            }
+        }
+        RemoteCommands::Ls {
+            remote,
+            transport,
+            ssh,
+            encryption,
+            install,
+        } => {
+            let conn = connect_remote(store, &remote, transport, ssh, encryption, &install)?;
+            let mut entries: Vec<Node> = conn.config()?.into_iter().collect();
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            match output {
+                Output::Text => {
+                    for node in &entries {
+                        println!("{}", node.name);
+                    }
+                }
+                Output::Json => {
+                    let entries: Vec<_> = entries
+                        .iter()
+                        .map(|node| json!({"name": node.name, "digest": node.digest()}))
+                        .collect();
+                    println!("{}", serde_json::to_string(&entries)?);
+                }
+            }
+        }
+        RemoteCommands::Rm {
+            remote,
+            names,
+            prune,
+            gc,
+            transport,
+            ssh,
+            encryption,
+            install,
         } => {
-            store.push(address, names, port, install, all)?;
+            let conn = connect_remote(store, &remote, transport, ssh, encryption, &install)?;
+            let mut removed = Vec::new();
+            conn.update_entries(&names, &mut |data| {
+                removed = names
+                    .iter()
+                    .filter(|name| data.remove(&Node::sample(name)))
+                    .cloned()
+                    .collect();
+            })?;
+            let missing: Vec<&str> = names
+                .iter()
+                .filter(|name| !removed.contains(name))
+                .map(|name| name.as_str())
+                .collect();
+            match output {
+                Output::Text => {
+                    for name in &removed {
+                        println!("{:<10} {}", "removed", name);
+                    }
+                }
+                Output::Json => println!("{}", json!({"removed": removed, "missing": missing})),
+            }
+            if prune {
+                report_gc(&conn.gc(false, gc.grace())?, false, output)?;
+            }
+            if !missing.is_empty() {
+                bail!("{} not found on the remote", missing.join(", "));
+            }
         }
+        RemoteCommands::Gc {
+            name,
+            dry_run,
+            gc,
+            transport,
+            ssh,
+            encryption,
+            install,
+        } => {
+            let conn = connect_remote(store, &name, transport, ssh, encryption, &install)?;
+            report_gc(&conn.gc(dry_run, gc.grace())?, dry_run, output)?;
+        }
+    }
+    Ok(())
+}
+
+/// 连接命名的远程或地址,用于只需要一个会话的remote命令
+fn connect_remote(
+    store: &Store,
+    remote: &str,
+    mut transport: Option<TransportKind>,
+    mut ssh: SshArgs,
+    mut encryption: EncryptArgs,
+    install: &InstallArgs,
+) -> anyhow::Result<Box<dyn Transport>> {
+    let address = remote::resolve(
+        &store.remotes()?,
+        remote,
+        &mut Vec::new(),
+        true,
+        &mut ssh,
+        &mut transport,
+        &mut encryption,
+    );
+    let mut conn = transport::connect(&address, transport, &ssh, install, &encryption, 1)?;
+    Ok(conn.remove(0))
+}
+
+/// 每个文件输出一行 `<digest>\t<size>`,最后输出回收的空间。
+/// 通过ssh清理时会解析文本输出,汇总行中不能有制表符
+fn report_gc(removed: &[(String, u64)], dry_run: bool, output: Output) -> anyhow::Result<()> {
+    let total: u64 = removed.iter().map(|(_, size)| size).sum();
+    if output == Output::Json {
+        let objects: Vec<_> = removed
+            .iter()
+            .map(|(digest, size)| json!({"digest": digest, "size": size}))
+            .collect();
+        let report = json!({"objects": objects, "bytes": total, "dry_run": dry_run});
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(());
+    }
+    for (digest, size) in removed {
+        println!("{}\t{}", digest, size);
     }
+    println!(
+        "{} {} bytes in {} objects",
+        if dry_run {
+            "would reclaim"
+        } else {
+            "reclaimed"
+        },
+        total,
+        removed.len()
+    );
     Ok(())
 }
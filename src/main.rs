@@ -7,7 +7,8 @@ use log::info;
 use hbx::run;
 
 fn main() {
-    set_var("RUST_LOG", "DEBUG");
+    // http客户端的调试日志过多,只保留警告
+    set_var("RUST_LOG", "DEBUG,ureq=warn,rustls=warn");
     let mut builder = env_logger::Builder::new();
     builder.format(|buf, record| writeln!(buf, "[ {} ] {}", buf.timestamp(), record.args()));
     builder.parse_default_env();
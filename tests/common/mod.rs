@@ -0,0 +1,148 @@
+//! 集成测试共用的工具,每个测试文件只用到其中一部分
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+pub const TOKEN: &str = "secret";
+
+/// 在随机端口上运行的 `hbx serve --http`,结束时关闭
+pub struct Server {
+    child: Child,
+    pub url: String,
+    pub home: TempDir,
+}
+
+impl Server {
+    pub fn start(token: Option<&str>) -> Self {
+        let home = TempDir::new().unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let mut cmd = hbx(home.path());
+        cmd.args(["serve", "--http", &addr]);
+        if let Some(token) = token {
+            cmd.args(["--token", token]);
+        }
+        let child = cmd.stderr(Stdio::null()).spawn().unwrap();
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        Self {
+            child,
+            url: format!("http://{}", addr),
+            home,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn hbx(home: &Path) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_hbx"));
+    cmd.env("HBX_HOME", home).env_remove("HBX_HTTP_TOKEN");
+    cmd
+}
+
+/// 运行命令,失败时输出标准错误
+pub fn run(cmd: &mut Command) -> Output {
+    let out = cmd.output().unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out
+}
+
+/// 运行预期失败的命令,返回标准错误
+pub fn fail(cmd: &mut Command) -> String {
+    let out = cmd.output().unwrap();
+    assert!(!out.status.success(), "command should fail");
+    String::from_utf8_lossy(&out.stderr).to_string()
+}
+
+pub fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).to_string()
+}
+
+/// 创建空的hbx存储,可以作为本地路径的远程
+pub fn init() -> TempDir {
+    let home = TempDir::new().unwrap();
+    run(hbx(home.path()).arg("info"));
+    home
+}
+
+/// 创建包含文件、子目录和符号链接的目录
+pub fn sample(dir: &Path, name: &str) -> PathBuf {
+    let root = dir.join(name);
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("a.txt"), format!("hello {}", name)).unwrap();
+    fs::write(root.join("sub").join("b.bin"), vec![7u8; 100_000]).unwrap();
+    std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+    root
+}
+
+/// 创建示例目录并添加到home,返回目录
+pub fn add_sample(home: &Path, src: &Path, name: &str) -> PathBuf {
+    let item = sample(src, name);
+    run(hbx(home).arg("add").arg(&item));
+    item
+}
+
+/// 带写入token推送,返回标准输出
+pub fn push(home: &Path, address: &str, args: &[&str]) -> String {
+    stdout(&run(hbx(home)
+        .env("HBX_HTTP_TOKEN", TOKEN)
+        .args(["push", address])
+        .args(args)))
+}
+
+/// 从home恢复条目,与原来的目录比较
+pub fn assert_restored(home: &Path, name: &str, item: &Path) {
+    let dst = TempDir::new().unwrap();
+    run(hbx(home).arg("get").arg(name).arg(dst.path()));
+    assert_same(item, &dst.path().join(name));
+}
+
+pub fn assert_same(a: &Path, b: &Path) {
+    assert_eq!(
+        fs::read(a.join("a.txt")).unwrap(),
+        fs::read(b.join("a.txt")).unwrap()
+    );
+    assert_eq!(
+        fs::read(a.join("sub").join("b.bin")).unwrap(),
+        fs::read(b.join("sub").join("b.bin")).unwrap()
+    );
+    assert_eq!(
+        fs::read_link(a.join("link")).unwrap(),
+        fs::read_link(b.join("link")).unwrap()
+    );
+}
+
+pub fn digest(data: &[u8]) -> String {
+    use md5::Digest;
+    format!("{:x}", md5::Md5::digest(data))
+}
+
+/// 示例目录中sub/b.bin的摘要
+pub fn big() -> String {
+    digest(&vec![7u8; 100_000])
+}
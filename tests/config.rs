@@ -0,0 +1,73 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+fn config(home: &std::path::Path) -> serde_json::Value {
+    serde_json::from_str(&fs::read_to_string(home.join("config")).unwrap()).unwrap()
+}
+
+#[test]
+fn legacy_config_upgrade() {
+    let home = TempDir::new().unwrap();
+    let hello = digest(b"hello\n");
+    fs::create_dir(home.path().join("store")).unwrap();
+    fs::write(home.path().join("store").join(&hello), "hello\n").unwrap();
+    let legacy = format!(r#"[{{"name":"x","meta":{{"FILE":"{}"}}}}]"#, hello);
+    fs::write(home.path().join("config"), &legacy).unwrap();
+
+    // 条目数组备份后转换为当前格式
+    assert_eq!(stdout(&run(hbx(home.path()).arg("list"))).trim(), "x");
+    assert_eq!(
+        fs::read_to_string(home.path().join("config.bak")).unwrap(),
+        legacy
+    );
+    let config = config(home.path());
+    assert_eq!(config["format_version"], 5);
+    assert_eq!(config["settings"]["layout"], "flat");
+    let manifest = config["index"]["x"].as_str().unwrap();
+    assert!(home.path().join("store").join(manifest).exists());
+
+    // 更新的格式拒绝读取
+    fs::write(
+        home.path().join("config"),
+        r#"{"format_version":99,"index":{}}"#,
+    )
+    .unwrap();
+    assert!(fail(hbx(home.path()).arg("list")).contains("format 99"));
+}
+
+#[test]
+fn entry_manifests() {
+    let home = TempDir::new().unwrap();
+    let src = TempDir::new().unwrap();
+    for name in ["tool", "other"] {
+        add_sample(home.path(), src.path(), name);
+    }
+    // 配置文件只保存索引,条目清单保存在存储中
+    let manifest = config(home.path())["index"]["tool"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(home.path().join("store").join(&manifest).exists());
+
+    // 导出的配置可以在另一个存储中条件导入
+    let exported = src.path().join("exported.json");
+    fs::write(
+        &exported,
+        run(hbx(home.path()).args(["export", "tool"])).stdout,
+    )
+    .unwrap();
+    let other = TempDir::new().unwrap();
+    let err = fail(
+        hbx(other.path())
+            .arg("import")
+            .arg(&exported)
+            .args(["--if-match", "stale"]),
+    );
+    assert!(err.contains("manifest changed"));
+    run(hbx(other.path()).arg("import").arg(&exported));
+    assert_eq!(stdout(&run(hbx(other.path()).arg("list"))).trim(), "tool");
+}
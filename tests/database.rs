@@ -0,0 +1,50 @@
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn sqlite_index() {
+    let home = TempDir::new().unwrap();
+    let src = TempDir::new().unwrap();
+    let item = add_sample(home.path(), src.path(), "tool");
+    let out = run(hbx(home.path()).args(["index", "import"]));
+    assert!(stdout(&out).contains("indexed 1 entries"));
+
+    // 索引随配置更新,可以由文件反查条目
+    add_sample(home.path(), src.path(), "other");
+    let refs = || stdout(&run(hbx(home.path()).args(["index", "refs", &big()])));
+    assert_eq!(refs(), "other\tother/sub/b.bin\ntool\ttool/sub/b.bin\n");
+    assert_eq!(
+        run(hbx(home.path()).args(["index", "export", "other"])).stdout,
+        run(hbx(home.path()).args(["export", "other"])).stdout
+    );
+
+    // gc根据引用计数删除不再被引用的文件
+    let old = digest(&fs::read(item.join("a.txt")).unwrap());
+    run(hbx(home.path()).args(["delete", "tool"]));
+    assert!(!home.path().join("store").join(&old).exists());
+    assert!(home.path().join("store").join(big()).exists());
+    assert_eq!(refs(), "other\tother/sub/b.bin\n");
+}
+
+#[test]
+fn load_from_sqlite_index() {
+    let home = TempDir::new().unwrap();
+    let src = TempDir::new().unwrap();
+    add_sample(home.path(), src.path(), "tool");
+    run(hbx(home.path()).args(["index", "import"]));
+    let expected = run(hbx(home.path()).arg("export")).stdout;
+
+    // 有索引时加载条目不再读取条目清单
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(home.path().join("config")).unwrap()).unwrap();
+    let manifest = config["index"]["tool"].as_str().unwrap();
+    fs::remove_file(home.path().join("store").join(manifest)).unwrap();
+    assert_eq!(run(hbx(home.path()).arg("export")).stdout, expected);
+    assert_eq!(stdout(&run(hbx(home.path()).arg("list"))).trim(), "tool");
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn encrypted_remote() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    let src = TempDir::new().unwrap();
+    let key = src.path().join("key");
+    fs::write(&key, "correct horse battery staple").unwrap();
+    let key = key.to_str().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    push(client.path(), address, &["tool", "--key-file", key]);
+
+    // 远程看不到条目名称和文件摘要
+    let index = fs::read_to_string(remote.path().join("config")).unwrap();
+    let export = stdout(&run(hbx(remote.path()).arg("export")));
+    for content in [&index, &export] {
+        assert!(!content.contains("tool"));
+        assert!(!content.contains(&big()));
+    }
+    assert!(!remote.path().join("store").join(big()).exists());
+
+    // 远程只看到加密的索引,没有可以读取或删除的条目
+    assert_eq!(stdout(&run(hbx(remote.path()).arg("list"))), "");
+    let config: serde_json::Value = serde_json::from_str(&index).unwrap();
+    assert!(config["sealed"]["index"].is_string());
+
+    // 加密后的文件被远程的引用列表引用,远程的gc不会删除
+    run(hbx(remote.path()).args(["gc", "--grace", "0"]));
+
+    // 同一文件的密文名称不变,推送引用相同文件的条目不会重复上传
+    let big_objects = || {
+        fs::read_dir(remote.path().join("store"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().metadata().unwrap().len() >= 100_000)
+            .count()
+    };
+    assert_eq!(big_objects(), 1);
+    add_sample(client.path(), src.path(), "other");
+    push(client.path(), address, &["other", "--key-file", key]);
+    assert_eq!(big_objects(), 1);
+
+    let other = TempDir::new().unwrap();
+    let wrong = src.path().join("wrong");
+    fs::write(&wrong, "wrong").unwrap();
+    fail(
+        hbx(other.path())
+            .args(["pull", address, "tool", "--key-file"])
+            .arg(&wrong),
+    );
+
+    run(hbx(other.path()).args(["pull", address, "tool", "-j", "2", "--key-file", key]));
+    assert_restored(other.path(), "tool", &item);
+}
+
+#[test]
+fn swapped_objects_rejected() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    let src = TempDir::new().unwrap();
+    let key = src.path().join("key");
+    fs::write(&key, "secret").unwrap();
+    let key = key.to_str().unwrap();
+    let client = TempDir::new().unwrap();
+    add_sample(client.path(), src.path(), "tool");
+    push(client.path(), address, &["tool", "--key-file", key]);
+
+    // 远程把一个文件换成另一个文件的密文,密文从条目的引用列表中找到
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(remote.path().join("config")).unwrap()).unwrap();
+    let store = remote.path().join("store");
+    let refs = config["sealed"]["refs"][0].as_str().unwrap();
+    let names: Vec<String> =
+        serde_json::from_str(&fs::read_to_string(store.join(refs)).unwrap()).unwrap();
+    let mut objects: Vec<(u64, std::path::PathBuf)> = names
+        .iter()
+        .map(|name| store.join(name))
+        .map(|p| (fs::metadata(&p).unwrap().len(), p))
+        .collect();
+    objects.sort();
+    // 条目清单和两个文件
+    assert_eq!(objects.len(), 3);
+    fs::copy(&objects[0].1, &objects[2].1).unwrap();
+
+    let other = TempDir::new().unwrap();
+    let err = fail(hbx(other.path()).args(["pull", address, "tool", "--key-file", key]));
+    assert!(err.contains("decrypt"), "{}", err);
+    assert_eq!(stdout(&run(hbx(other.path()).arg("list"))), "");
+}
+
+#[test]
+fn relay_between_encrypted_remotes() {
+    let src = TempDir::new().unwrap();
+    let (a, b) = (src.path().join("a"), src.path().join("b"));
+    fs::write(&a, "key a").unwrap();
+    fs::write(&b, "key b").unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    let source = init();
+    let source_address = source.path().to_str().unwrap();
+    push(
+        client.path(),
+        source_address,
+        &["tool", "--key-file", a.to_str().unwrap()],
+    );
+
+    let target = init();
+    let relay = TempDir::new().unwrap();
+    run(hbx(relay.path())
+        .arg("relay")
+        .args([source_address, target.path().to_str().unwrap(), "tool"])
+        .arg("--src-key-file")
+        .arg(&a)
+        .arg("--dst-key-file")
+        .arg(&b));
+    assert!(!fs::read_to_string(target.path().join("config"))
+        .unwrap()
+        .contains("tool"));
+
+    let other = TempDir::new().unwrap();
+    run(hbx(other.path())
+        .args([
+            "pull",
+            target.path().to_str().unwrap(),
+            "tool",
+            "--key-file",
+        ])
+        .arg(&b));
+    assert_restored(other.path(), "tool", &item);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn prune_after_replace() {
+    let server = Server::start(Some(TOKEN));
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    push(client.path(), &server.url, &["tool"]);
+
+    // 替换后旧版本的a.txt不再被引用
+    let old = digest(&fs::read(item.join("a.txt")).unwrap());
+    fs::write(item.join("a.txt"), "changed").unwrap();
+    run(hbx(client.path()).args(["delete", "tool"]));
+    run(hbx(client.path()).arg("add").arg(&item));
+    push(client.path(), &server.url, &["tool"]);
+    let objects = server.home.path().join("store");
+    assert!(objects.join(&old).exists());
+
+    // 默认保留最近写入的文件
+    let gc = |args: &[&str]| {
+        stdout(&run(hbx(client.path())
+            .env("HBX_HTTP_TOKEN", TOKEN)
+            .args(["remote", "gc", &server.url])
+            .args(args)))
+    };
+    assert!(gc(&[]).contains("reclaimed 0 bytes"));
+
+    let out = gc(&["--dry-run", "--grace", "0"]);
+    assert!(out.contains(&format!("{}\t10", old)), "{}", out);
+    assert!(objects.join(&old).exists());
+
+    // 旧版本的条目清单也不再被引用
+    let out = push(
+        client.path(),
+        &server.url,
+        &["tool", "--prune", "--grace", "0"],
+    );
+    assert!(out.contains(&format!("{}\t10", old)), "{}", out);
+    assert!(out.contains("in 2 objects"), "{}", out);
+    assert!(!objects.join(&old).exists());
+    assert_restored(server.home.path(), "tool", &item);
+
+    // gc需要token
+    fail(hbx(client.path()).args(["remote", "gc", &server.url]));
+}
+
+#[test]
+fn gc_endpoint_grace() {
+    let server = Server::start(Some(TOKEN));
+    let content = b"unreferenced".to_vec();
+    let digest = digest(&content);
+    ureq::put(&format!("{}/objects/{}", server.url, digest))
+        .set("Authorization", &format!("Bearer {}", TOKEN))
+        .send_bytes(&content)
+        .unwrap();
+    let gc = |query: &str| {
+        let res = ureq::post(&format!("{}/gc{}", server.url, query))
+            .set("Authorization", &format!("Bearer {}", TOKEN))
+            .call()
+            .unwrap();
+        serde_json::from_reader::<_, serde_json::Value>(res.into_reader()).unwrap()
+    };
+
+    // 与 `hbx gc` 相同,默认保留最近写入的文件
+    assert_eq!(gc(""), serde_json::json!([]));
+    assert_eq!(
+        gc("?grace=0&dry_run=true"),
+        serde_json::json!([[digest, 12]])
+    );
+    assert!(server.home.path().join("store").join(&digest).exists());
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn pull_from_read_only_server() {
+    let server = Server::start(None);
+    let src = TempDir::new().unwrap();
+    let item = add_sample(server.home.path(), src.path(), "tool");
+
+    let client = TempDir::new().unwrap();
+    run(hbx(client.path()).args(["pull", &server.url, "tool"]));
+    assert_eq!(stdout(&run(hbx(client.path()).arg("list"))).trim(), "tool");
+    assert_restored(client.path(), "tool", &item);
+
+    // 只读服务器拒绝推送
+    add_sample(client.path(), src.path(), "other");
+    fail(hbx(client.path()).args(["push", &server.url, "other"]));
+}
+
+#[test]
+fn push_with_token() {
+    let server = Server::start(Some(TOKEN));
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+
+    // 没有token时拒绝推送
+    fail(hbx(client.path()).args(["push", &server.url, "tool"]));
+
+    push(client.path(), &server.url, &["tool", "-j", "2"]);
+    assert_eq!(
+        stdout(&run(hbx(server.home.path()).arg("list"))).trim(),
+        "tool"
+    );
+    assert_restored(server.home.path(), "tool", &item);
+}
+
+#[test]
+fn object_endpoints() {
+    let server = Server::start(Some(TOKEN));
+    let content = b"0123456789".to_vec();
+    let digest = digest(&content);
+    let url = format!("{}/objects/{}", server.url, digest);
+
+    let res = ureq::head(&url).call();
+    assert!(matches!(res, Err(ureq::Error::Status(404, _))));
+
+    // 内容与摘要不一致时拒绝
+    let res = ureq::put(&url)
+        .set("Authorization", &format!("Bearer {}", TOKEN))
+        .send_bytes(b"other");
+    assert!(matches!(res, Err(ureq::Error::Status(400, _))));
+
+    ureq::put(&url)
+        .set("Authorization", &format!("Bearer {}", TOKEN))
+        .send_bytes(&content)
+        .unwrap();
+    let res = ureq::head(&url).call().unwrap();
+    assert_eq!(res.header("Content-Length"), Some("10"));
+    let etag = res.header("ETag").unwrap().to_string();
+
+    // 断点续传
+    let res = ureq::get(&url)
+        .set("Range", "bytes=4-")
+        .set("If-Range", &etag)
+        .call()
+        .unwrap();
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.header("Content-Range"), Some("bytes 4-9/10"));
+    assert_eq!(res.into_string().unwrap(), "456789");
+
+    // If-Range不匹配时返回完整文件
+    let res = ureq::get(&url)
+        .set("Range", "bytes=4-")
+        .set("If-Range", "\"stale\"")
+        .call()
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.into_string().unwrap(), "0123456789");
+
+    let res = ureq::get(&format!("{}/objects/..%2Fconfig", server.url)).call();
+    assert!(matches!(res, Err(ureq::Error::Status(400, _))));
+}
+
+#[test]
+fn compressed_object_range() {
+    let server = Server::start(Some(TOKEN));
+    run(hbx(server.home.path()).args(["config", "--compression", "zstd"]));
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    add_sample(client.path(), src.path(), "tool");
+    push(client.path(), &server.url, &["tool"]);
+
+    // 压缩的文件解压后按原始内容的偏移续传
+    let res = ureq::get(&format!("{}/objects/{}", server.url, big()))
+        .set("Range", "bytes=99990-")
+        .call()
+        .unwrap();
+    assert_eq!(res.status(), 206);
+    assert_eq!(
+        res.header("Content-Range"),
+        Some("bytes 99990-99999/100000")
+    );
+}
+
+#[test]
+fn manifest_conditional_write() {
+    let server = Server::start(Some(TOKEN));
+    let url = format!("{}/manifest", server.url);
+    let res = ureq::get(&url).call().unwrap();
+    let etag = res.header("ETag").unwrap().to_string();
+    let body = res.into_string().unwrap();
+
+    let put = |etag: Option<&str>, token: &str| {
+        let mut req = ureq::put(&url).set("Authorization", &format!("Bearer {}", token));
+        if let Some(etag) = etag {
+            req = req.set("If-Match", etag);
+        }
+        match req.send_string(&body) {
+            Ok(res) => res.status(),
+            Err(ureq::Error::Status(code, _)) => code,
+            Err(e) => panic!("{}", e),
+        }
+    };
+    assert_eq!(put(Some("\"stale\""), TOKEN), 412);
+    assert_eq!(put(Some(&etag), TOKEN), 200);
+    // 没有If-Match时无条件写入
+    assert_eq!(put(None, TOKEN), 200);
+    assert_eq!(put(None, "secreT"), 401);
+    assert_eq!(put(None, "secret2"), 401);
+}
+
+#[test]
+fn index_endpoint() {
+    let server = Server::start(Some(TOKEN));
+    let src = TempDir::new().unwrap();
+    for name in ["tool", "other"] {
+        add_sample(server.home.path(), src.path(), name);
+    }
+    let config: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(server.home.path().join("config")).unwrap())
+            .unwrap();
+    let url = format!("{}/index", server.url);
+    let res = ureq::get(&url).call().unwrap();
+    let etag = res.header("ETag").unwrap().to_string();
+    let mut index: serde_json::Value = serde_json::from_reader(res.into_reader()).unwrap();
+    assert_eq!(index["index"], config["index"]);
+
+    // 条件替换索引,索引引用的条目清单必须已经在存储中
+    let put = |index: &serde_json::Value, etag: &str| {
+        let req = ureq::put(&url)
+            .set("Authorization", &format!("Bearer {}", TOKEN))
+            .set("If-Match", etag);
+        match req.send_string(&index.to_string()) {
+            Ok(res) => res.status(),
+            Err(ureq::Error::Status(code, _)) => code,
+            Err(e) => panic!("{}", e),
+        }
+    };
+    index["index"].as_object_mut().unwrap().remove("other");
+    assert_eq!(put(&index, "\"stale\""), 412);
+    index["index"]["missing"] = serde_json::json!("0123456789abcdef0123456789abcdef");
+    assert_eq!(put(&index, &etag), 500);
+    index["index"].as_object_mut().unwrap().remove("missing");
+    assert_eq!(put(&index, &etag), 200);
+    assert_eq!(
+        stdout(&run(hbx(server.home.path()).arg("list"))).trim(),
+        "tool"
+    );
+
+    // 拉取单个条目只下载该条目的清单
+    let client = TempDir::new().unwrap();
+    run(hbx(client.path()).args(["pull", &server.url, "tool"]));
+    assert_eq!(stdout(&run(hbx(client.path()).arg("list"))).trim(), "tool");
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn relay_between_remotes() {
+    let src = TempDir::new().unwrap();
+    let build = TempDir::new().unwrap();
+    let item = add_sample(build.path(), src.path(), "tool");
+    let target = init();
+
+    // 本地存储只用于读取命名远程,中转的文件不写入本地
+    let client = TempDir::new().unwrap();
+    let relay = || {
+        stdout(&run(hbx(client.path())
+            .arg("relay")
+            .arg(build.path())
+            .arg(target.path())
+            .args(["tool", "-j", "2"])))
+    };
+    assert!(relay().contains("created"));
+    assert_eq!(
+        fs::read_dir(client.path().join("store")).unwrap().count(),
+        0
+    );
+    assert_restored(target.path(), "tool", &item);
+    assert!(relay().contains("unchanged"));
+}
+
+#[test]
+fn relay_reads_only_named_entries() {
+    let src = TempDir::new().unwrap();
+    let build = init();
+    let item = add_sample(build.path(), src.path(), "tool");
+    add_sample(build.path(), src.path(), "other");
+    let target = init();
+
+    // 删除other的清单后,只中转tool时不需要读取它
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(build.path().join("config")).unwrap()).unwrap();
+    let manifest = config["index"]["other"].as_str().unwrap();
+    fs::remove_file(build.path().join("store").join(manifest)).unwrap();
+
+    let client = TempDir::new().unwrap();
+    let relay = |args: &[&str]| {
+        let mut cmd = hbx(client.path());
+        cmd.arg("relay")
+            .arg(build.path())
+            .arg(target.path())
+            .args(args);
+        cmd
+    };
+    run(&mut relay(&["tool"]));
+    assert_restored(target.path(), "tool", &item);
+    fail(&mut relay(&["-a"]));
+}
@@ -0,0 +1,98 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn remote_ls_and_rm() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    for name in ["tool", "other"] {
+        add_sample(client.path(), src.path(), name);
+    }
+    push(client.path(), address, &["-a"]);
+
+    let out = run(hbx(client.path()).args(["remote", "ls", address]));
+    assert_eq!(stdout(&out), "other\ntool\n");
+
+    let out = run(hbx(client.path()).args(["remote", "rm", address, "other", "--output", "json"]));
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["removed"], serde_json::json!(["other"]));
+
+    let out = run(hbx(client.path()).args(["remote", "ls", address, "--output", "json"]));
+    let entries: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["name"], "tool");
+
+    // 不存在的条目返回错误
+    fail(hbx(client.path()).args(["remote", "rm", address, "other"]));
+}
+
+#[test]
+fn local_remote_must_exist() {
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    add_sample(client.path(), src.path(), "tool");
+
+    // 不存在的路径和不是存储的目录都不会被当作远程创建
+    let missing = TempDir::new().unwrap();
+    let address = missing.path().join("remote");
+    let err = fail(hbx(client.path()).arg("push").arg(&address).arg("tool"));
+    assert!(err.contains("not a hbx store"), "{}", err);
+    assert!(!address.exists());
+
+    let err = fail(
+        hbx(client.path())
+            .arg("push")
+            .arg(missing.path())
+            .arg("tool"),
+    );
+    assert!(err.contains("not a hbx store"), "{}", err);
+    assert_eq!(fs::read_dir(missing.path()).unwrap().count(), 0);
+
+    let remote = init();
+    push(client.path(), remote.path().to_str().unwrap(), &["tool"]);
+    assert_eq!(stdout(&run(hbx(remote.path()).arg("list"))).trim(), "tool");
+}
+
+#[test]
+fn named_remotes() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    add_sample(client.path(), src.path(), "tool");
+    add_sample(client.path(), src.path(), "other");
+    run(hbx(client.path()).args(["remote", "add", "nas", address, "--entry", "tool"]));
+    fail(hbx(client.path()).args(["remote", "add", "nas", address]));
+
+    // 远程保存在配置文件中
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(client.path().join("config")).unwrap()).unwrap();
+    assert_eq!(config["remotes"]["nas"]["url"], address);
+    assert_eq!(
+        config["remotes"]["nas"]["entries"],
+        serde_json::json!(["tool"])
+    );
+    let list = stdout(&run(hbx(client.path()).args(["remote", "list"])));
+    assert_eq!(list, format!("nas\t{} entries=tool\n", address));
+
+    // 未指定条目时推送默认条目
+    run(hbx(client.path()).args(["push", "nas"]));
+    assert_eq!(stdout(&run(hbx(remote.path()).arg("list"))).trim(), "tool");
+    let show = stdout(&run(hbx(client.path()).args(["remote", "show", "nas"])));
+    let lines: Vec<&str> = show.lines().skip(1).collect();
+    assert_eq!(lines, ["ahead      other", "up-to-date tool"]);
+
+    run(hbx(client.path()).args(["remote", "remove", "nas"]));
+    assert_eq!(
+        stdout(&run(hbx(client.path()).args(["remote", "list"]))),
+        ""
+    );
+    fail(hbx(client.path()).args(["remote", "remove", "nas"]));
+    fail(hbx(client.path()).args(["push", "nas"]));
+}
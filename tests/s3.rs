@@ -0,0 +1,246 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::*;
+use tempfile::TempDir;
+use tiny_http::{Header, Method, Request, Response};
+
+const BUCKET: &str = "bucket";
+/// 每页返回的对象数,小于测试中的对象数以覆盖分页
+const PAGE: usize = 2;
+
+/// 对象键到内容和写入时间
+type Objects = BTreeMap<String, (Vec<u8>, SystemTime)>;
+
+/// 内存中的S3替身,实现hbx用到的path-style接口: 对象的GET、PUT和DELETE,
+/// If-Match/If-None-Match条件写入,以及分页的ListObjectsV2
+struct Stub {
+    url: String,
+    objects: Arc<Mutex<Objects>>,
+}
+
+impl Stub {
+    fn start() -> Self {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        let state = objects.clone();
+        thread::spawn(move || {
+            for req in server.incoming_requests() {
+                handle(req, &state);
+            }
+        });
+        Self { url, objects }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn hbx(&self, home: &std::path::Path) -> std::process::Command {
+        let mut cmd = hbx(home);
+        cmd.env("AWS_ENDPOINT_URL", &self.url)
+            .env("AWS_ACCESS_KEY_ID", "test")
+            .env("AWS_SECRET_ACCESS_KEY", "secret")
+            .env("AWS_REGION", "us-east-1")
+            .env_remove("AWS_SESSION_TOKEN");
+        cmd
+    }
+}
+
+fn handle(mut req: Request, objects: &Mutex<Objects>) {
+    let authorized = header(&req, "Authorization").is_some_and(|a| {
+        a.starts_with("AWS4-HMAC-SHA256 Credential=test/") && a.contains("Signature=")
+    }) && header(&req, "x-amz-date").is_some();
+    if !authorized {
+        let _ = req.respond(Response::empty(403));
+        return;
+    }
+    let url = req.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let key = match path.strip_prefix(&format!("/{}/", BUCKET)) {
+        Some(key) => decode(key),
+        None => {
+            let _ = req.respond(Response::empty(404));
+            return;
+        }
+    };
+    let etag = |data: &[u8]| format!("\"{}\"", digest(data));
+    let mut objects = objects.lock().unwrap();
+    let res = match (req.method(), key.as_str()) {
+        (Method::Get, "") => {
+            let query: BTreeMap<String, String> = query
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (decode(k), decode(v)))
+                .collect();
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let after = query.get("continuation-token").cloned().unwrap_or_default();
+            let matched: Vec<_> = objects
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix) && **k > after)
+                .collect();
+            let mut xml = String::from("<ListBucketResult>");
+            for (k, (data, modified)) in matched.iter().take(PAGE) {
+                xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+                    k,
+                    timestamp(*modified),
+                    data.len()
+                ));
+            }
+            if matched.len() > PAGE {
+                xml.push_str(&format!(
+                    "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                    matched[PAGE - 1].0
+                ));
+            } else {
+                xml.push_str("<IsTruncated>false</IsTruncated>");
+            }
+            xml.push_str("</ListBucketResult>");
+            Response::from_string(xml).boxed()
+        }
+        (Method::Get, key) => match objects.get(key) {
+            // 与S3一样总是返回Content-Length,不使用分块传输
+            Some((data, _)) => Response::from_data(data.clone())
+                .with_chunked_threshold(usize::MAX)
+                .with_header(Header::from_bytes("ETag", etag(data)).unwrap())
+                .boxed(),
+            None => Response::empty(404).boxed(),
+        },
+        (Method::Put, key) => {
+            let current = objects.get(key).map(|(data, _)| etag(data));
+            let allowed = match (
+                header(&req, "If-Match"),
+                header(&req, "If-None-Match"),
+                &current,
+            ) {
+                (Some(expected), _, Some(current)) => &expected == current,
+                (Some(_), _, None) => false,
+                (None, Some(_), current) => current.is_none(),
+                (None, None, _) => true,
+            };
+            if allowed {
+                let mut data = Vec::new();
+                req.as_reader().read_to_end(&mut data).unwrap();
+                let res = Response::empty(200)
+                    .with_header(Header::from_bytes("ETag", etag(&data)).unwrap());
+                objects.insert(key.to_string(), (data, SystemTime::now()));
+                res.boxed()
+            } else {
+                Response::empty(412).boxed()
+            }
+        }
+        (Method::Delete, key) => {
+            objects.remove(key);
+            Response::empty(204).boxed()
+        }
+        _ => Response::empty(405).boxed(),
+    };
+    drop(objects);
+    let _ = req.respond(res);
+}
+
+fn header(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.to_string().eq_ignore_ascii_case(name))
+        .map(|h| h.value.to_string())
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            ans.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            ans.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(ans).unwrap()
+}
+
+/// `2026-01-02T03:04:05.000Z` 格式的时间
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[test]
+fn s3_remote() {
+    let stub = Stub::start();
+    let address = format!("s3://{}/backup", BUCKET);
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    add_sample(client.path(), src.path(), "other");
+    run(stub.hbx(client.path()).args(["push", &address, "-a"]));
+    assert!(stub.keys().iter().all(|k| k.starts_with("backup/")));
+    // 条目清单与文件一起保存,索引只记录条目名称到清单摘要
+    assert!(stub.keys().contains(&"backup/index.json".to_string()));
+    assert!(!stub.keys().contains(&"backup/manifest.json".to_string()));
+    assert!(stub.keys().contains(&format!("backup/objects/{}", big())));
+
+    let out = run(stub.hbx(client.path()).args(["remote", "ls", &address]));
+    assert_eq!(stdout(&out), "other\ntool\n");
+
+    let other = TempDir::new().unwrap();
+    run(stub.hbx(other.path()).args(["pull", &address, "tool"]));
+    assert_restored(other.path(), "tool", &item);
+
+    // 删除条目后,只有它引用的文件被回收,默认保留最近写入的文件
+    run(stub
+        .hbx(client.path())
+        .args(["remote", "rm", &address, "other"]));
+    let gc = |args: &[&str]| {
+        stdout(&run(stub
+            .hbx(client.path())
+            .args(["remote", "gc", &address])
+            .args(args)))
+    };
+    assert!(gc(&[]).contains("reclaimed 0 bytes"));
+    let unique = format!("backup/objects/{}", digest(b"hello other"));
+    assert!(stub.keys().contains(&unique));
+    let out = gc(&["--grace", "0"]);
+    assert!(out.contains(&digest(b"hello other")), "{}", out);
+    assert!(!stub.keys().contains(&unique));
+    assert!(stub.keys().contains(&format!("backup/objects/{}", big())));
+
+    let again = TempDir::new().unwrap();
+    run(stub.hbx(again.path()).args(["pull", &address, "tool"]));
+    assert_restored(again.path(), "tool", &item);
+    fail(stub.hbx(again.path()).args(["pull", &address, "other"]));
+
+    // 凭证错误时请求被拒绝
+    fail(
+        stub.hbx(client.path())
+            .env("AWS_ACCESS_KEY_ID", "wrong")
+            .args(["remote", "ls", &address]),
+    );
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+use tempfile::TempDir;
+
+#[test]
+fn compressed_store() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    run(hbx(remote.path()).args(["config", "--compression", "zstd"]));
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    push(client.path(), address, &["tool"]);
+
+    // 远程保存压缩后的文件,仍按原始内容的摘要读取
+    let objects = remote.path().join("store");
+    assert!(objects.join(format!("{}.zst", big())).exists());
+    assert!(!objects.join(big()).exists());
+
+    // 只有指定 --sizes 时才统计文件大小
+    let info = |args: &[&str]| -> serde_json::Value {
+        serde_json::from_slice(&run(hbx(remote.path()).arg("info").args(args)).stdout).unwrap()
+    };
+    assert!(info(&[]).get("logical_size").is_none());
+    let sizes = info(&["--sizes"]);
+    let size = |key: &str| sizes[key].as_str().unwrap().parse::<u64>().unwrap();
+    assert!(size("physical_size") < size("logical_size"));
+
+    let other = TempDir::new().unwrap();
+    run(hbx(other.path()).args(["pull", address, "tool"]));
+    assert_restored(other.path(), "tool", &item);
+}
+
+#[test]
+fn chunked_files() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    let client = TempDir::new().unwrap();
+    run(hbx(client.path()).args(["config", "--chunk-threshold", "1M"]));
+
+    // 伪随机内容,第二版在中间插入少量数据
+    let mut state = 1u64;
+    let v1: Vec<u8> = (0..6_000_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let mut v2 = v1.clone();
+    v2.splice(3_000_000..3_000_000, vec![1u8; 1000]);
+    let src = TempDir::new().unwrap();
+    for (name, data) in [("v1", &v1), ("v2", &v2)] {
+        fs::create_dir(src.path().join(name)).unwrap();
+        fs::write(src.path().join(name).join("img"), data).unwrap();
+        run(hbx(client.path()).arg("add").arg(src.path().join(name)));
+    }
+
+    let objects = || fs::read_dir(remote.path().join("store")).unwrap().count();
+    push(client.path(), address, &["v1"]);
+    let before = objects();
+    assert!(before > 2);
+    push(client.path(), address, &["v2"]);
+    // 只有插入位置附近的块和条目清单不同
+    assert!(objects() - before <= 3);
+
+    let other = TempDir::new().unwrap();
+    run(hbx(other.path()).args(["pull", address, "v2"]));
+    let dst = TempDir::new().unwrap();
+    run(hbx(other.path()).arg("get").arg("v2").arg(dst.path()));
+    assert_eq!(fs::read(dst.path().join("v2").join("img")).unwrap(), v2);
+}
+
+#[test]
+fn fanout_layout() {
+    let remote = init();
+    let address = remote.path().to_str().unwrap();
+    run(hbx(remote.path()).args(["migrate", "--layout", "fanout"]));
+    let src = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    let item = add_sample(client.path(), src.path(), "tool");
+    push(client.path(), address, &["tool"]);
+
+    let big = big();
+    let objects = remote.path().join("store");
+    assert!(objects.join(&big[..2]).join(&big[2..]).exists());
+    assert!(!objects.join(&big).exists());
+
+    // 迁移回flat布局后仍可以拉取,条目清单与文件一起移动
+    let out = run(hbx(remote.path()).args(["migrate", "--layout", "flat"]));
+    assert!(stdout(&out).starts_with("moved 3 objects"));
+    assert!(objects.join(&big).exists());
+    let other = TempDir::new().unwrap();
+    run(hbx(other.path()).args(["pull", address, "tool"]));
+    assert_restored(other.path(), "tool", &item);
+}